@@ -27,6 +27,11 @@
       type = types.str;
       default = "courier";
     };
+    extraConfig = mkOption {
+      type = types.attrs;
+      default = {};
+      description = "Additional settings merged into the generated config.yaml.";
+    };
   };
 
   config =
@@ -39,7 +44,7 @@
           priv_key_file = cfg.privKeyFile;
           pub_key_file = cfg.pubKeyFile;
           db = "host=/var/run/postgresql user=${cfg.user} dbname=${cfg.database}";
-        });
+        } // cfg.extraConfig);
       inherit (self.packages.${pkgs.system}) courier;
     in
       lib.mkIf cfg.enable {
@@ -9,6 +9,7 @@ pub struct Actor {
     pub actor_type: String,
     pub id: String,
     pub name: Option<String>,
+    pub summary: Option<String>,
     pub icon: Option<Media>,
     pub inbox: String,
     #[serde(rename = "publicKey")]
@@ -38,6 +39,29 @@ pub struct Action<O> {
     pub object: Option<O>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Note {
+    #[serde(rename = "type")]
+    pub note_type: String,
+    pub id: String,
+    #[serde(rename = "attributedTo")]
+    pub attributed_to: String,
+    #[serde(rename = "inReplyTo")]
+    pub in_reply_to: Option<String>,
+    pub to: Vec<String>,
+    pub tag: Vec<Tag>,
+    pub content: String,
+    pub published: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    #[serde(rename = "type")]
+    pub tag_type: String,
+    pub href: String,
+    pub name: String,
+}
+
 impl IntoResponse for Actor {
     fn into_response(self) -> axum::response::Response {
         ([("content-type", "application/activity+json")],
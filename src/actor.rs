@@ -36,7 +36,7 @@ impl Actor {
     pub fn from_uri(uri: &str) -> Result<Self, Error> {
         let parsed = reqwest::Url::parse(uri).map_err(|_| Error::InvalidUri)?;
         let host = Arc::new(parsed.host_str().ok_or_else(|| Error::InvalidUri)?.to_string());
-        match parsed.path_segments().ok_or_else(|| Error::InvalidUri)?.last() {
+        match parsed.path_segments().ok_or_else(|| Error::InvalidUri)?.next_back() {
             Some("completion") => Ok(Actor {
                 host,
                 kind: ActorKind::CompletionRelay,
@@ -71,6 +71,7 @@ impl Actor {
                 ActorKind::CompletionRelay => "Courier Six - Mission Complete".to_string(),
                 ActorKind::TrendsRelay(instance) => format!("Courier Six - Trends from [{instance}]"),
             }),
+            summary: None,
             icon: Some(activitypub::Media {
                 media_type: "Image".to_string(),
                 content_type: "image/jpeg".to_string(),
@@ -0,0 +1,120 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+use futures::StreamExt;
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use reqwest::Client;
+use sigh::PrivateKey;
+use crate::{api::FediApi, post::{link_parents, Post}, actor::{Actor, RemoteActor}, error::Error, db::Database, replies};
+
+// Replies waiting for their root thread, beyond which new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+// Replies completed at the same time
+const CONCURRENCY: usize = 8;
+
+struct Context {
+    actor: Arc<Actor>,
+    db: Database,
+    client: Arc<Client>,
+    private_key: PrivateKey,
+    tx: Sender<(Arc<Actor>, Arc<RemoteActor>, Arc<Post>)>,
+}
+
+/// Queue of replies whose root thread is to be completed for a follower.
+/// A reply is only queued once until it has been completed.
+#[derive(Clone)]
+pub struct Ancestors {
+    tx: Sender<(Arc<RemoteActor>, Post)>,
+    queued: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl Ancestors {
+    pub fn queue(&self, remote_actor: Arc<RemoteActor>, reply: Post) {
+        let key = (remote_actor.id.clone(), reply.uri.clone());
+        if !self.queued.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        if let Err(e) = self.tx.try_send((remote_actor, reply)) {
+            self.queued.lock().unwrap().remove(&key);
+            match e {
+                TrySendError::Full((_, reply)) => tracing::warn!("ancestors: queue full, dropping {}", reply.uri),
+                TrySendError::Closed((_, reply)) => tracing::error!("ancestors: queue closed, dropping {}", reply.uri),
+            }
+        }
+    }
+}
+
+// Ancestors of a reply, root first, through the api of its host
+async fn get_ancestors(reply: &Post, ctx: &Context) -> Result<Vec<Post>, Error> {
+    let host = reply.host()
+        .ok_or_else(|| Error::Api(format!("Failed to get host of {}", reply.uri)))?;
+    let api = FediApi::from_host(&host, &ctx.db, &ctx.client).await?;
+    api.get_ancestors_of(reply, &ctx.client).await
+}
+
+// Monitors the root thread of a reply, and announces the root if the remote
+// actor did not have it yet. The other ancestors are descendants of the root
+// and get relayed by completion.
+async fn complete_ancestors(remote_actor: &Arc<RemoteActor>, reply: &Post, ctx: &Context) -> Result<(), Error> {
+    let (root, ancestors) = match ctx.db.get_ancester_of(&reply.uri).await? {
+        Some(root) => (Post::from_uri(root), None),
+        None => match get_ancestors(reply, ctx).await {
+            Ok(ancestors) => {
+                let mut ancestors = ancestors.into_iter();
+                let root = ancestors.next()
+                    .ok_or_else(|| Error::Api(format!("Failed to find the ancestors of {}", reply.uri)))?;
+                (root, Some(ancestors))
+            },
+            // Any server can be walked up through ActivityPub, though only the
+            // root is kept then
+            Err(e) => {
+                tracing::warn!("ancestors: get {} through api, falling back to inReplyTo: {:?}", reply.uri, e);
+                let root = replies::get_root_of(&reply.uri, &ctx.actor.key_id(), &ctx.private_key).await?;
+                (root, None)
+            },
+        },
+    };
+    let newly_monitored = ctx.db.monitor_post(remote_actor, &root).await?;
+    if let Some(ancestors) = ancestors {
+        let mut thread: Vec<Post> = ancestors.chain(std::iter::once(reply.clone())).collect();
+        link_parents(&root, &mut thread);
+        ctx.db.insert_descendants(&root, thread.into_iter()).await?;
+    }
+    if newly_monitored {
+        ctx.tx.send((ctx.actor.clone(), remote_actor.clone(), Arc::new(root))).await
+            .map_err(|e| Error::Api(format!("Failed to announce the root of {}: {:?}", reply.uri, e)))?;
+    }
+    Ok(())
+}
+
+/// Completes queued replies in the background, so that fetching their
+/// ancestors does not hold up whoever found them.
+pub fn spawn(actor: Actor, db: Database, client: Arc<Client>, private_key: PrivateKey, tx: Sender<(Arc<Actor>, Arc<RemoteActor>, Arc<Post>)>) -> Ancestors {
+    let (queue_tx, queue_rx) = channel(QUEUE_SIZE);
+    let ancestors = Ancestors {
+        tx: queue_tx,
+        queued: Arc::new(Mutex::new(HashSet::new())),
+    };
+    let queued = ancestors.queued.clone();
+    let ctx = Context {
+        actor: Arc::new(actor),
+        db,
+        client,
+        private_key,
+        tx,
+    };
+    tokio::spawn(async move {
+        let replies = futures::stream::unfold(queue_rx, |mut rx| async move {
+            rx.recv().await.map(|reply| (reply, rx))
+        });
+        replies.for_each_concurrent(CONCURRENCY, |(remote_actor, reply): (Arc<RemoteActor>, Post)| {
+            let ctx = &ctx;
+            let queued = &queued;
+            async move {
+                if let Err(e) = complete_ancestors(&remote_actor, &reply, ctx).await {
+                    tracing::warn!("ancestors: complete ancestors of {}: {:?}", reply.uri, e);
+                }
+                queued.lock().unwrap().remove(&(remote_actor.id.clone(), reply.uri));
+            }
+        }).await;
+    });
+    ancestors
+}
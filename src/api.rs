@@ -1,329 +1,481 @@
-use std::time::Duration;
-use futures::future::join_all;
-use serde::Deserialize;
-use serde_json::{json, Value, Map};
+use futures::{stream::BoxStream, StreamExt};
+use axum::async_trait;
+use serde_json::{json, Value};
 use reqwest::{Client, StatusCode};
-use async_recursion::async_recursion;
-use crate::{db::Database, post::Post, error::Error};
+use crate::{db::Database, post::{link_parents, Post}, error::Error, request::{self, Operation}};
+
+mod mastodon;
+mod akkoma;
+mod gotosocial;
+mod misskey;
+mod lemmy;
+mod mbin;
+
+// Seconds until the api type of an instance is detected again
+const DETECT_TTL: i64 = 7 * 86400;
+// Seconds until a failed detection is retried, doubled with every further
+// failure up to `DETECT_TTL`
+const DETECT_RETRY: i64 = 600;
+// Unreachable hosts are retried at least this often
+const DETECT_RETRY_UNREACHABLE: i64 = 3600;
+
+/// The client api of some fediverse software. Backends are registered in
+/// `BACKENDS` under the software names that speak them.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Checks that the token belongs to an administrator of the host.
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error>;
+
+    async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error>;
+
+    /// Returns posts after `since_id`, oldest first
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error>;
+
+    /// Backends without streaming are polled instead.
+    async fn stream_global_timeline(&self, host: &str, _client: &Client) -> Result<BoxStream<'static, Result<Post, Error>>, Error> {
+        Err(Error::Stream(format!("{host}: streaming is not supported")))
+    }
 
-// FIXME: Refactor for better extensibility
+    /// Looks up the api id of a post that was not seen in a response of
+    /// the host.
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error>;
 
-pub enum FediApi {
-    Mastodon,
-    Misskey,
-    Calckey,
-}
+    /// Fetches a single post, which is much cheaper than its context
+    async fn get_post(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Post, Error>;
 
-#[derive(Deserialize)]
-struct Context {
-    // ancestors: Vec<Post>,
-    descendants: Vec<Post>,
+    /// Returns the ancestors of a post, starting from the root
+    async fn get_ancestors_of(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error>;
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, db: &Database, client: &Client) -> Result<Vec<Post>, Error>;
 }
 
-impl FediApi {
+struct Registration {
+    /// Stored as the api type of instances
+    api_type: &'static str,
+    /// NodeInfo software names
+    software: &'static [&'static str],
+    /// Whether the api lists trending posts
+    trends: bool,
+    /// Creates the backend with an optional api token
+    new: fn(Option<String>) -> Box<dyn Backend>,
+}
 
-    pub async fn determine(host: &str, client: &Client) -> Result<Self, Error> {
-        let mastodon_meta_url = format!("https://{host}/api/v1/instance");
-        let misskey_meta_url = format!("https://{host}/api/meta");
-
-        let res = client.get(mastodon_meta_url)
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        let mastodon_compatible = res.status() == StatusCode::OK;
-
-        let res = client.post(misskey_meta_url)
-            .json(&json!({ "detail": false }))
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        let misskey_compatible = res.status() == StatusCode::OK;
-
-        match (mastodon_compatible, misskey_compatible) {
-            (true, true)   => Ok(FediApi::Calckey),
-            (true, false)  => Ok(FediApi::Mastodon),
-            (false, true)  => Ok(FediApi::Misskey),
-            (false, false) => Err(Error::Api(format!("Failed to determine api variant of {host}"))),
-        }
+const BACKENDS: &[Registration] = &[
+    Registration {
+        api_type: "mastodon",
+        software: &["mastodon", "hometown", "glitchsoc"],
+        trends: true,
+        new: mastodon::Mastodon::boxed,
+    },
+    Registration {
+        api_type: "akkoma",
+        software: &["pleroma", "akkoma"],
+        trends: false,
+        new: akkoma::Akkoma::boxed,
+    },
+    Registration {
+        api_type: "gotosocial",
+        software: &["gotosocial"],
+        trends: false,
+        new: gotosocial::GoToSocial::boxed,
+    },
+    Registration {
+        api_type: "misskey",
+        software: &["misskey", "foundkey", "cherrypick"],
+        trends: true,
+        new: misskey::Misskey::boxed,
+    },
+    // Misskey forks that also serve the Mastodon api
+    Registration {
+        api_type: "calckey",
+        software: &["calckey", "firefish", "iceshrimp", "sharkey", "catodon"],
+        trends: true,
+        new: misskey::Misskey::boxed,
+    },
+    Registration {
+        api_type: "lemmy",
+        software: &["lemmy"],
+        trends: true,
+        new: lemmy::Lemmy::boxed,
+    },
+    Registration {
+        api_type: "mbin",
+        software: &["kbin", "mbin"],
+        trends: true,
+        new: mbin::Mbin::boxed,
+    },
+];
+
+impl Registration {
+    fn of(api_type: &str) -> Result<&'static Self, Error> {
+        BACKENDS.iter()
+            .find(|registration| registration.api_type == api_type)
+            .ok_or_else(|| Error::Api(format!("Unknown host type: {api_type}")))
     }
 
-    pub async fn from_host(host: &str, db: &Database, client: &Client) -> Result<Self, Error> {
-        match db.get_instance_type(host).await {
-            Ok(instance_type) => {
-                Self::from_str(&instance_type)
-            },
-            Err(_) => {
-                let host_api = Self::determine(host, client).await?;
-                db.add_instance(host, &host_api.to_str()).await?;
-                Ok(host_api)
-            }
-        }
+    fn for_software(name: &str) -> Option<&'static Self> {
+        BACKENDS.iter()
+            .find(|registration| registration.software.contains(&name))
     }
+}
 
-    pub fn from_str(host_type: &str) -> Result<Self, Error> {
-        match host_type {
-            "mastodon" => Ok(FediApi::Mastodon),
-            "misskey"  => Ok(FediApi::Misskey),
-            "calckey"  => Ok(FediApi::Calckey),
-            _          => Err(Error::Api(format!("Unknown host type: {host_type}"))),
-        }
-    }
+/// Software as reported by NodeInfo
+struct Software {
+    name: String,
+    version: Option<String>,
+}
 
-    pub fn to_str(&self) -> String {
-        match self {
-            FediApi::Mastodon => "mastodon".to_string(),
-            FediApi::Misskey => "misskey".to_string(),
-            FediApi::Calckey => "calckey".to_string(),
-        }
+/// The NodeInfo index of a host
+struct NodeInfoIndex {
+    index: Value,
+    /// Host that served the index after redirects
+    served_by: Option<String>,
+}
+
+/// Detects the api through NodeInfo, and falls back to probing api
+/// endpoints for software that is unknown or without NodeInfo.
+async fn determine(host: &str, index: Option<&NodeInfoIndex>, client: &Client) -> Result<(&'static Registration, Option<Software>), Error> {
+    let software = match get_software(host, index, client).await {
+        Ok(software) => software,
+        Err(e) => {
+            tracing::warn!("Failed to get NodeInfo of {}, probing instead: {:?}", host, e);
+            None
+        },
+    };
+    if let Some(registration) = software.as_ref().and_then(|software| Registration::for_software(&software.name)) {
+        return Ok((registration, software));
     }
+    let registration = probe(host, client).await.map_err(|e| match (e, &software) {
+        (Error::Unsupported(host, _), Some(software)) => Error::Unsupported(host, format!("{} speaks no known api", software.name)),
+        (e, _) => e,
+    })?;
+    Ok((registration, software))
+}
 
-    pub async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error> {
-        match self {
-            FediApi::Mastodon => Self::mastodon_get_trending_posts(host, client).await,
-            _                 => Self::misskey_get_trending_posts(host, client).await,
-        }
+// Schema versions sort lexically, so take the newest one
+fn nodeinfo_href(index: &Value) -> Option<&str> {
+    index["links"].as_array()
+        .into_iter()
+        .flatten()
+        .filter(|link| link["rel"].as_str()
+                .map_or(false, |rel| rel.starts_with("http://nodeinfo.diaspora.software/ns/schema/")))
+        .max_by_key(|link| link["rel"].as_str().map(str::to_string))
+        .and_then(|link| link["href"].as_str())
+}
+
+fn host_of_url(url: &str) -> Option<String> {
+    reqwest::Url::parse(url).ok()?
+        .host_str()
+        .map(str::to_lowercase)
+}
+
+// The WebFinger template of an XRD `host-meta` document
+fn lrdd_template(host_meta: &str) -> Option<&str> {
+    host_meta.split("<Link")
+        .skip(1)
+        .find(|link| link.contains("\"lrdd\""))
+        .and_then(|link| link.split("template=\"").nth(1))
+        .and_then(|template| template.split('"').next())
+}
+
+/// The host serving the api of an instance. Instances with split domains
+/// use their account domain in uris, while `host-meta` and NodeInfo lead
+/// to the web domain, either by a redirect or by their links.
+async fn get_api_host(host: &str, index: Option<&NodeInfoIndex>, client: &Client) -> String {
+    match get_linked_host(host, index, client).await {
+        Ok(Some(api_host)) => api_host,
+        Ok(None) => host.to_string(),
+        Err(e) => {
+            tracing::warn!("Failed to resolve the api host of {}: {:?}", host, e);
+            host.to_string()
+        },
     }
+}
 
-    pub async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
-        match self {
-            FediApi::Mastodon => Self::mastodon_get_global_timeline(host, since_id, client).await,
-            _                 => Self::misskey_get_global_timeline(host, since_id, client).await,
+async fn get_linked_host(host: &str, index: Option<&NodeInfoIndex>, client: &Client) -> Result<Option<String>, Error> {
+    let res = request::send(client.get(format!("https://{host}/.well-known/host-meta")), Operation::Detect).await?;
+    if res.status() == StatusCode::OK {
+        let redirected = host_of_url(res.url().as_str());
+        let host_meta = request::text(res).await?;
+        let linked = lrdd_template(&host_meta).and_then(host_of_url).or(redirected);
+        if linked.as_deref().map_or(false, |linked| linked != host) {
+            return Ok(linked);
         }
     }
 
-    // pub async fn get_ancester_of(&self, post: &Post) -> Result<Post, Error> {
-    //     match self {
-    //         FediApi::Mastodon => Self::mastodon_get_ancester_of(&post).await,
-    //         _                 => Self::misskey_get_ancester_of(&post).await,
-    //     }
-    // }
+    let Some(index) = index else {
+        return Ok(None);
+    };
+    let linked = nodeinfo_href(&index.index).and_then(host_of_url).or_else(|| index.served_by.clone());
+    Ok(linked.filter(|linked| linked != host))
+}
 
-    pub async fn get_descendants_of(&self, post: &Post, client: &Client) -> Result<Vec<Post>, Error> {
-        match self {
-            FediApi::Mastodon => Self::mastodon_get_descendants_of(post, client).await,
-            _                 => Self::misskey_get_descendants_of(post, client).await,
-        }
+async fn get_nodeinfo_index(host: &str, client: &Client) -> Result<Option<NodeInfoIndex>, Error> {
+    let res = request::send(client.get(format!("https://{host}/.well-known/nodeinfo")), Operation::Detect).await?;
+    if res.status() != StatusCode::OK {
+        return Ok(None);
     }
+    let served_by = host_of_url(res.url().as_str());
+    Ok(Some(NodeInfoIndex {
+        index: request::json(res).await?,
+        served_by,
+    }))
+}
 
-    async fn mastodon_get_trending_posts(host: &str, client: &Client) -> Result<Vec<Post>, Error> {
-        let trends_url = format!("https://{host}/api/v1/trends/statuses?limit=10");
-        let res = client.get(trends_url)
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        if res.status() != StatusCode::OK {
-            return Err(Error::Api(format!("Failed to get trending posts of {}: status: {}, response: {}",
-                                           host, res.status(), res.text().await?)));
-        }
-        
-        let posts: Vec<Post> = res.json().await?;
-        Ok(posts.into_iter().map(|post| post.origin()).collect())
+// Only NodeInfo on the api host, or on the host that served the index, is
+// trusted.
+async fn get_software(host: &str, index: Option<&NodeInfoIndex>, client: &Client) -> Result<Option<Software>, Error> {
+    let Some((index, href)) = index.and_then(|index| Some((index, nodeinfo_href(&index.index)?))) else {
+        return Ok(None);
+    };
+    let trusted = host_of_url(href)
+        .map_or(false, |href_host| href_host == host || Some(&href_host) == index.served_by.as_ref());
+    if !trusted {
+        return Err(Error::Api(format!("NodeInfo of {host} is linked on another host: {href}")));
     }
 
-    async fn mastodon_get_global_timeline(host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
-        let timeline_url = match since_id {
-            Some(id) => format!("https://{}/api/v1/timelines/public?limit=40?since_id={}", host, id),
-            None => format!("https://{}/api/v1/timelines/public?limit=40", host),
-        };
-        let res = client.get(timeline_url)
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        if res.status() != StatusCode::OK {
-            return Err(Error::Api(format!("Failed to get global timeline of {}: status: {}, response: {}",
-                                           host, res.status(), res.text().await?)));
+    let res = request::send(client.get(href), Operation::Detect).await?;
+    let nodeinfo: Value = request::json(res).await?;
+    Ok(nodeinfo["software"]["name"].as_str().map(|name| Software {
+        name: name.to_lowercase(),
+        version: nodeinfo["software"]["version"].as_str().map(str::to_string),
+    }))
+}
+
+async fn probe(host: &str, client: &Client) -> Result<&'static Registration, Error> {
+    let mastodon_meta_url = format!("https://{host}/api/v1/instance");
+    let misskey_meta_url = format!("https://{host}/api/meta");
+
+    // Only a host that answered is unsupported, errors of the host itself
+    // are worth another try.
+    let answered = |res: &reqwest::Response| {
+        let status = res.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Error::Status(res.url().to_string(), status))
+        } else {
+            Ok(status == StatusCode::OK)
         }
+    };
+
+    let res = request::send(client.get(mastodon_meta_url), Operation::Detect).await?;
+    let mastodon_compatible = answered(&res)?;
+
+    let res = request::send(
+        client.post(misskey_meta_url)
+            .json(&json!({ "detail": false })),
+        Operation::Detect,
+    ).await?;
+    let misskey_compatible = answered(&res)?;
+
+    let api_type = match (mastodon_compatible, misskey_compatible) {
+        (true, true)   => "calckey",
+        (true, false)  => "mastodon",
+        (false, true)  => "misskey",
+        (false, false) => return Err(Error::Unsupported(host.to_string(), "no known api answered".to_string())),
+    };
+    Registration::of(api_type)
+}
 
-        let mut posts: Vec<Post> = res.json().await?;
-        posts.sort_by_key(|p| p.created_at.clone().unwrap());
-        Ok(posts)
-    }
+// Boosted and quoted posts come with their ids on the host too
+fn ids_of(posts: &[Post]) -> Vec<(String, String)> {
+    posts.iter()
+        .flat_map(|post| [Some(post), post.reblog.as_deref(), post.quote.as_deref()])
+        .flatten()
+        .filter_map(|post| post.timeline_id.clone().map(|id| (post.uri.clone(), id)))
+        .collect()
+}
 
-    // async fn mastodon_get_ancester_of(post: &Post) -> Result<Post, Error> {
-    //     if !post.is_reply() {
-    //         return Ok(post.clone());
-    //     }
-
-    //     let context_url = format!("https://{}/api/v1/statuses/{}/context",
-    //                               post.host().ok_or_else(|| Error::Api(format!("Failed to get host of {}", post.uri)))?,
-    //                               post.id().ok_or_else(|| Error::Api(format!("Failed to get id of {}", post.uri)))?);
-
-    //     let client = Client::new();
-    //     let res = client.get(context_url)
-    //         .timeout(Duration::MAX)
-    //         .send()
-    //         .await
-    //         .map_err(Error::Http)?;
-    //     if res.status() != StatusCode::OK {
-    //         return Err(Error::Api(format!("Failed to get context of {}", post.uri)));
-    //     }
-
-    //     let context: Context = serde_json::from_str(&res.text().await?).map_err(Error::Json)?;         
-    //     Ok(context.ancestors.into_iter().find(|post| !post.is_reply())
-    //         .ok_or_else(|| Error::Api(format!("Failed to find the ancestor of {}", post.uri)))?)
-    // }
-
-    async fn mastodon_get_descendants_of(post: &Post, client: &Client) -> Result<Vec<Post>, Error> {
-        let context_url = format!("https://{}/api/v1/statuses/{}/context",
-                                  post.host().ok_or_else(|| Error::Api(format!("Failed to get host of {}", post.uri)))?,
-                                  post.id().ok_or_else(|| Error::Api(format!("Failed to get id of {}", post.uri)))?);
-
-        let res = client.get(context_url)
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        if res.status() != StatusCode::OK {
-            return Err(Error::Api(format!("Failed to get context of {}: status: {}, response: {}",
-                                           post.uri, res.status(), res.text().await?)));
-        }
+/// Why trending posts of a host can't be relayed, as far as known without
+/// contacting it
+pub async fn trends_problem(host: &str, db: &Database) -> Result<Option<String>, Error> {
+    let Some(instance) = db.get_instance(host).await? else { return Ok(None) };
+    Ok(match instance.api_type {
+        Some(api_type) => Registration::of(&api_type).ok()
+            .filter(|registration| !registration.trends)
+            .map(|_| format!("{host} does not provide trending posts")),
+        None => instance.detect_failure,
+    })
+}
 
-        let context: Context = res.json().await?;         
-        Ok(context.descendants)
-    }
+pub struct FediApi {
+    /// The host serving the api, which may differ from the host in uris
+    api_host: String,
+    db: Database,
+    registration: &'static Registration,
+    backend: Box<dyn Backend>,
+}
 
-    fn misskey_post_supplement_uri(host: &str, post: &mut Map<String, Value>) -> Result<(), Error> {
-        let id_value = post.get("id")
-            .ok_or_else(||Error::Api(format!("Failed to parse response from {host}: missing field `id`")))?
-            .clone();
-        let id = id_value.as_str()
-            .ok_or_else(||Error::Api(format!("Failed to parse response from {host}: `id` is not string")))?;
-        post.entry("uri".to_string()).or_insert(serde_json::to_value(
-                format!("https://{}/notes/{}", host, id))?);
-
-        if let Some(Value::Object(renote)) = post.get_mut("renote") {
-            Self::misskey_post_supplement_uri(host, renote)?;
-        }
+impl FediApi {
 
-        if let Some(Value::Object(reply)) = post.get_mut("reply") {
-            Self::misskey_post_supplement_uri(host, reply)?;
+    // The token is a Mastodon bearer token or a Misskey `i` token
+    fn new(api_host: String, db: Database, registration: &'static Registration, token: Option<String>) -> Self {
+        FediApi {
+            api_host,
+            db,
+            registration,
+            backend: (registration.new)(token),
         }
+    }
 
-        Ok(())
+    pub async fn from_host(host: &str, db: &Database, client: &Client) -> Result<Self, Error> {
+        let instance = db.get_instance(host).await?;
+        let token = instance.as_ref().and_then(|instance| instance.token.clone());
+        let known = instance.as_ref()
+            .and_then(|instance| instance.api_type.as_deref().map(|api_type| (api_type, instance.detect_time)));
+        let known_api_host = instance.as_ref()
+            .and_then(|instance| instance.api_host.clone())
+            .unwrap_or_else(|| host.to_string());
+        let now = chrono::Utc::now().timestamp();
+        let retry_time = instance.as_ref().and_then(|instance| instance.detect_retry_time);
+        let (registration, api_host) = match known {
+            Some((api_type, Some(detect_time))) if detect_time > now - DETECT_TTL =>
+                (Registration::of(api_type)?, known_api_host),
+            Some((api_type, _)) if retry_time.map_or(false, |retry_time| retry_time > now) =>
+                (Registration::of(api_type)?, known_api_host),
+            // Failed detections are not repeated before their retry time
+            None if retry_time.map_or(false, |retry_time| retry_time > now) => {
+                let failure = instance.as_ref().and_then(|instance| instance.detect_failure.clone());
+                return Err(match failure {
+                    Some(failure) => Error::Unsupported(host.to_string(), failure),
+                    None => Error::DetectPending(host.to_string(), retry_time.unwrap_or(now).saturating_sub(now).unsigned_abs()),
+                });
+            },
+            // Re-detected now and then, as instances may migrate software
+            _ => {
+                // The index is fetched once, for the api host and the software
+                let index = match get_nodeinfo_index(host, client).await {
+                    Ok(index) => index,
+                    Err(e) => {
+                        tracing::warn!("Failed to get the NodeInfo index of {}: {:?}", host, e);
+                        None
+                    },
+                };
+                let api_host = get_api_host(host, index.as_ref(), client).await;
+                let index = match index {
+                    None if api_host != host => get_nodeinfo_index(&api_host, client).await.unwrap_or_else(|e| {
+                        tracing::warn!("Failed to get the NodeInfo index of {}: {:?}", api_host, e);
+                        None
+                    }),
+                    index => index,
+                };
+                match determine(&api_host, index.as_ref(), client).await {
+                    Ok((registration, software)) => {
+                        if let Some(instance) = &instance {
+                            let name = software.as_ref().map(|software| software.name.as_str());
+                            let version = software.as_ref().and_then(|software| software.version.as_deref());
+                            if instance.software.is_some() && (instance.software.as_deref(), instance.version.as_deref()) != (name, version) {
+                                tracing::info!("{} moved from {:?} {:?} to {:?} {:?}", host, instance.software, instance.version, name, version);
+                            }
+                        }
+                        if api_host != host {
+                            tracing::info!("{} serves its api on {}", host, api_host);
+                        }
+                        db.add_instance(host, registration.api_type,
+                                        software.as_ref().map(|software| software.name.as_str()),
+                                        software.as_ref().and_then(|software| software.version.as_deref()),
+                                        &api_host).await?;
+                        (registration, api_host)
+                    },
+                    // Only unsupported hosts are reported as such, unreachable
+                    // ones are retried sooner.
+                    Err(e) => {
+                        let failures = instance.as_ref().map_or(0, |instance| instance.detect_failures);
+                        let (failure, max_delay) = match &e {
+                            Error::Unsupported(_, reason) => (Some(reason.as_str()), DETECT_TTL),
+                            _ => (None, DETECT_RETRY_UNREACHABLE),
+                        };
+                        let delay = DETECT_RETRY.saturating_mul(1 << failures.clamp(0, 16)).min(max_delay);
+                        db.add_instance_failure(host, failure, now + delay).await?;
+                        match known {
+                            Some((api_type, _)) => {
+                                tracing::warn!("Failed to re-detect api of {}, keeping {}: {:?}", host, api_type, e);
+                                (Registration::of(api_type)?, known_api_host)
+                            },
+                            None => {
+                                tracing::warn!("Failed to detect api of {}, retrying in {}s: {:?}", host, delay, e);
+                                return Err(e);
+                            },
+                        }
+                    },
+                }
+            },
+        };
+        Ok(FediApi::new(api_host, db.clone(), registration, token))
     }
 
-    async fn misskey_posts_from_response(host: &str, response: reqwest::Response) -> Result<Vec<Post>, Error> {
-        let mut posts: Vec<Map<String, Value>> = response.json().await?;
-        for post in &mut posts {
-            Self::misskey_post_supplement_uri(host, post)?;
-        }
-        let value = serde_json::to_value(posts)?;
-        Ok(serde_json::from_value(value)?)
+    pub fn with_token(self, token: String) -> Self {
+        FediApi::new(self.api_host, self.db, self.registration, Some(token))
     }
 
-    async fn misskey_get_trending_posts(host: &str, client: &Client) -> Result<Vec<Post>, Error> {
-        let trends_url = format!("https://{host}/api/notes/featured");
-        let res = client.post(trends_url)
-            .json(&json!({ "limit": 10 }))
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        if res.status() != StatusCode::OK {
-            return Err(Error::Api(format!("Failed to get trending posts of {}: status: {}, response: {}",
-                                           host, res.status(), res.text().await?)));
+    /// The api id of a post on this host. Ids seen in responses of the host
+    /// are remembered, anything else is looked up once.
+    async fn id_of(&self, post: &Post, client: &Client) -> Result<String, Error> {
+        if let Some(id) = self.db.get_api_id(&self.api_host, &post.uri).await? {
+            return Ok(id);
         }
-        
-        let posts = Self::misskey_posts_from_response(host, res).await?;
-        Ok(posts.into_iter().map(|post| post.origin()).collect())
+        let id = self.backend.lookup_id(&self.api_host, post, client).await?;
+        self.db.add_api_ids(&self.api_host, std::iter::once((post.uri.clone(), id.clone()))).await?;
+        Ok(id)
     }
 
-    async fn misskey_get_global_timeline(host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
-        let timeline_url = format!("https://{}/api/notes/global-timeline", host);
-        let body_json = match since_id {
-            Some(id) => json!({ "limit": 100, "sinceId": id }),
-            None     => json!({ "limit": 100 }),
-        };
+    async fn remember_ids(&self, posts: &[Post]) -> Result<(), Error> {
+        self.db.add_api_ids(&self.api_host, ids_of(posts).into_iter()).await?;
+        Ok(())
+    }
 
-        let res = client.post(timeline_url)
-            .json(&body_json)
-            .timeout(Duration::MAX)
-            .send()
-            .await
-            .map_err(Error::Http)?;
-        if res.status() != StatusCode::OK {
-            return Err(Error::Api(format!("Failed to get global timline of {}: status: {}, response: {}",
-                                           host, res.status(), res.text().await?)));
-        }
+    /// Checks that the token belongs to an administrator of the host.
+    pub async fn verify_admin_token(&self, client: &Client) -> Result<(), Error> {
+        self.backend.verify_admin_token(&self.api_host, client).await
+    }
 
-        let mut posts = Self::misskey_posts_from_response(host, res).await?;
-        posts.sort_by_key(|p| p.created_at.clone().unwrap());
+    pub async fn get_trending_posts(&self, client: &Client) -> Result<Vec<Post>, Error> {
+        let posts = self.backend.get_trending_posts(&self.api_host, client).await?;
+        self.remember_ids(&posts).await?;
         Ok(posts)
     }
 
-    // async fn misskey_get_ancester_of(post: &Post) -> Result<Post, Error> {
-    //     if !post.is_reply() {
-    //         return Ok(post.clone());
-    //     }
-    //     let conversation_url = format!("https://{}/api/notes/conversation",
-    //                               post.host().ok_or_else(|| Error::Api(format!("Failed to get host of {}", post.uri)))?);
-
-    //     let client = Client::new();
-    //     let res = client.post(conversation_url)
-    //         .body(to_vec(&json!({ "noteId": post.id().ok_or_else(|| Error::Api(format!("Failed to get id of {}", post.uri)))? }))?)
-    //         .timeout(Duration::MAX)
-    //         .send()
-    //         .await
-    //         .map_err(Error::Http)?;
-    //     if res.status() != StatusCode::OK {
-    //         return Err(Error::Api(format!("Failed to get conversation of {}", post.uri)));
-    //     }
-
-    //     let conversation: Vec<Post> = serde_json::from_str(&res.text().await?).map_err(Error::Json)?;         
-    //     Ok(conversation.into_iter().find(|p| !p.is_reply())
-    //         .ok_or_else(|| Error::Api(format!("Failed to find the ancestor of {}", post.uri)))?)
-    // }
-
-    #[async_recursion]
-    async fn misskey_get_replies(uri: &str, host: &str, timeline_id: &str, client: &Client) -> Result<Vec<Post>, Error> {
-        let replies_url = format!("https://{}/api/notes/children", &host);
-
-        let mut replies: Vec<Post> = vec![];
-        let mut since_id = String::from("0");
-        loop {
-            let res = client.post(replies_url.clone())
-                .json(&json!({ "noteId": timeline_id, "sinceId": since_id, "limit": 100 }))
-                .timeout(Duration::MAX)
-                .send()
-                .await
-                .map_err(Error::Http)?;
-            if res.status() != StatusCode::OK {
-                return Err(Error::Api(format!("Failed to get replies of {}: status: {}, response: {}",
-                                               uri, res.status(), res.text().await?)));
-            }
+    pub async fn get_global_timeline(&self, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        let posts = self.backend.get_global_timeline(&self.api_host, since_id, client).await?;
+        self.remember_ids(&posts).await?;
+        Ok(posts)
+    }
 
-            let mut new_replies: Vec<Post> = Self::misskey_posts_from_response(host, res).await?;
-            if new_replies.is_empty() {
-                break;
+    pub async fn stream_global_timeline(&self, client: &Client) -> Result<BoxStream<'static, Result<Post, Error>>, Error> {
+        let posts = self.backend.stream_global_timeline(&self.api_host, client).await?;
+        let (db, api_host) = (self.db.clone(), self.api_host.clone());
+        Ok(posts.then(move |post| {
+            let (db, api_host) = (db.clone(), api_host.clone());
+            async move {
+                if let Ok(post) = &post {
+                    if let Err(e) = db.add_api_ids(&api_host, ids_of(std::slice::from_ref(post)).into_iter()).await {
+                        tracing::warn!("Failed to remember the api id of {}: {:?}", post.uri, e);
+                    }
+                }
+                post
             }
-            new_replies.sort_by_key(|p| p.created_at.clone().unwrap());
-            since_id = new_replies.last()
-                                  .unwrap()
-                                  .timeline_id.clone()
-                                  .ok_or_else(||Error::Api("Post does not have a timeline id".to_string()))?;
-            replies.append(&mut new_replies);
-        }
+        }).boxed())
+    }
+
+    /// Fetches a single post, which is much cheaper than its context
+    pub async fn get_post(&self, post: &Post, client: &Client) -> Result<Post, Error> {
+        let id = self.id_of(post, client).await?;
+        self.backend.get_post(&self.api_host, post, &id, client).await
+    }
 
-        let recursive_replies = join_all(replies.clone()
-                                         .into_iter()
-                                         .map(|p| async move {
-                                             let id = p.timeline_id
-                                                       .ok_or_else(||Error::Api(format!("{uri}: Posts does not have a timeline_id")))?;
-                                             Self::misskey_get_replies(uri, host, &id, client).await
-                                         }))
-                                         .await
-                                         .into_iter()
-                                         .collect::<Result<Vec<Vec<Post>>, Error>>()?;
-        Ok(recursive_replies.into_iter().fold(replies, |mut acc, val| { acc.extend(val); acc }))
+    /// Returns the ancestors of a post, starting from the root
+    pub async fn get_ancestors_of(&self, post: &Post, client: &Client) -> Result<Vec<Post>, Error> {
+        let id = self.id_of(post, client).await?;
+        let ancestors = self.backend.get_ancestors_of(&self.api_host, post, &id, client).await?;
+        self.remember_ids(&ancestors).await?;
+        Ok(ancestors)
     }
 
-    async fn misskey_get_descendants_of(post: &Post, client: &Client) -> Result<Vec<Post>, Error> {
-        let host = post.host().ok_or_else(|| Error::Api(format!("Failed to get host of {}", post.uri)))?;
-        let id = post.id().ok_or_else(|| Error::Api(format!("Failed to get id of {}", post.uri)))?;
-        Self::misskey_get_replies(&post.uri, &host, &id, client).await
+    pub async fn get_descendants_of(&self, post: &Post, client: &Client) -> Result<Vec<Post>, Error> {
+        let id = self.id_of(post, client).await?;
+        let post = Post { timeline_id: Some(id.clone()), ..post.clone() };
+        let mut descendants = self.backend.get_descendants_of(&self.api_host, &post, &id, &self.db, client).await?;
+        self.remember_ids(&descendants).await?;
+        link_parents(&post, &mut descendants);
+        Ok(descendants)
     }
 }
@@ -0,0 +1,56 @@
+use axum::async_trait;
+use serde_json::Value;
+use reqwest::Client;
+use crate::{db::Database, post::Post, error::Error, request::{self, Operation}};
+use super::{mastodon::Mastodon, Backend};
+
+/// Pleroma and Akkoma. Their statuses are addressed by flake ids that are
+/// unrelated to the object uris, and they have no trends.
+pub struct Akkoma {
+    mastodon: Mastodon,
+}
+
+impl Akkoma {
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(Akkoma { mastodon: Mastodon::new(token) })
+    }
+}
+
+#[async_trait]
+impl Backend for Akkoma {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let res = request::send(self.mastodon.auth(client.get(format!("https://{host}/api/v1/accounts/verify_credentials"))), Operation::Post).await?;
+        let account: Value = request::json(res).await?;
+        if account["pleroma"]["is_admin"] == true || account["pleroma"]["is_moderator"] == true {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, _client: &Client) -> Result<Vec<Post>, Error> {
+        Err(Error::Api(format!("{host} does not provide trending posts")))
+    }
+
+    // Instances restricting unauthenticated access need a token here
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        self.mastodon.get_global_timeline(host, since_id, client).await
+    }
+
+    // Objects live at `/objects/<uuid>`, which is not the api id
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        self.mastodon.search_id(host, post, client).await
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        self.mastodon.get_status(host, id, client).await
+    }
+
+    async fn get_ancestors_of(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        Ok(self.mastodon.get_context(host, post, id, client).await?.ancestors)
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, _db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        Ok(self.mastodon.get_context(host, post, id, client).await?.descendants)
+    }
+}
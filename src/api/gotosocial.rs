@@ -0,0 +1,55 @@
+use axum::async_trait;
+use serde_json::Value;
+use reqwest::Client;
+use crate::{db::Database, post::Post, error::Error, request::{self, Operation}};
+use super::{mastodon::Mastodon, Backend};
+
+/// GoToSocial. Most of its api, including the public timeline and search,
+/// requires a token, and it has no trends.
+pub struct GoToSocial {
+    mastodon: Mastodon,
+}
+
+impl GoToSocial {
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(GoToSocial { mastodon: Mastodon::new(token) })
+    }
+}
+
+#[async_trait]
+impl Backend for GoToSocial {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let res = request::send(self.mastodon.auth(client.get(format!("https://{host}/api/v1/accounts/verify_credentials"))), Operation::Post).await?;
+        let account: Value = request::json(res).await?;
+        if account["role"]["name"] == "admin" || account["role"]["name"] == "moderator" {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, _client: &Client) -> Result<Vec<Post>, Error> {
+        Err(Error::Api(format!("{host} does not provide trending posts")))
+    }
+
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        self.mastodon.get_global_timeline(host, since_id, client).await
+    }
+
+    // Statuses share the uri layout of Mastodon
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        self.mastodon.lookup_id(host, post, client).await
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        self.mastodon.get_status(host, id, client).await
+    }
+
+    async fn get_ancestors_of(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        Ok(self.mastodon.get_context(host, post, id, client).await?.ancestors)
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, _db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        Ok(self.mastodon.get_context(host, post, id, client).await?.descendants)
+    }
+}
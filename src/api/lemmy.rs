@@ -0,0 +1,162 @@
+use axum::async_trait;
+use serde_json::Value;
+use reqwest::{Client, RequestBuilder};
+use crate::{db::Database, post::Post, error::Error, request::{self, Operation}};
+use super::Backend;
+
+const COMMENTS_LIMIT: usize = 50;
+// Upper bound of comment pages fetched per post
+const MAX_COMMENT_PAGES: usize = 20;
+
+/// Lemmy. Posts and comments are mapped to their ActivityPub `ap_id`, so
+/// that followers fetch them from their origin.
+pub struct Lemmy {
+    token: Option<String>,
+}
+
+impl Lemmy {
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(Lemmy { token })
+    }
+
+    fn auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn get(&self, url: String, operation: Operation, client: &Client) -> Result<Value, Error> {
+        let res = request::send(self.auth(client.get(url)), operation).await?;
+        request::json(res).await
+    }
+
+    // `post_view` of a post, with its counts
+    fn post_of(view: &Value) -> Option<Post> {
+        let mut post = Post::from_uri(view["post"]["ap_id"].as_str()?.to_string());
+        post.timeline_id = view["post"]["id"].as_i64().map(|id| id.to_string());
+        post.created_at = view["post"]["published"].as_str().map(str::to_string);
+        post.replies_count = view["counts"]["comments"].as_i64();
+        Some(post)
+    }
+
+    // The path of a comment lists the ids from the root `0` down to itself
+    fn parent_id_of(comment: &Value) -> Option<String> {
+        let path: Vec<&str> = comment["path"].as_str()?.split('.').collect();
+        match path.as_slice() {
+            [.., parent, _] if *parent != "0" => Some(parent.to_string()),
+            _ => None,
+        }
+    }
+
+    // `comment_view` of a comment; direct replies to the post are linked to it
+    fn comment_of(view: &Value, post: &Post) -> Option<Post> {
+        let comment = &view["comment"];
+        let mut reply = Post::from_uri(comment["ap_id"].as_str()?.to_string());
+        reply.timeline_id = comment["id"].as_i64().map(|id| id.to_string());
+        reply.created_at = comment["published"].as_str().map(str::to_string);
+        reply.replies_count = view["counts"]["child_count"].as_i64();
+        match Self::parent_id_of(comment) {
+            Some(parent_id) => reply.in_reply_to_id = Some(parent_id),
+            None => {
+                reply.in_reply_to_id = post.timeline_id.clone();
+                reply.in_reply_to = Some(post.uri.clone());
+            },
+        }
+        Some(reply)
+    }
+}
+
+#[async_trait]
+impl Backend for Lemmy {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let site = self.get(format!("https://{host}/api/v3/site"), Operation::Post, client).await?;
+        if site["my_user"]["local_user_view"]["local_user"]["admin"] == true {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let posts = self.get(format!("https://{host}/api/v3/post/list?sort=Hot&type_=All&limit=10"), Operation::Timeline, client).await?;
+        Ok(posts["posts"].as_array()
+           .into_iter()
+           .flatten()
+           .filter_map(Self::post_of)
+           .collect())
+    }
+
+    // There is no cursor, so newer posts are picked from the newest page
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        let posts = self.get(format!("https://{host}/api/v3/post/list?sort=New&type_=Local&limit=50"), Operation::Timeline, client).await?;
+        let since_id: i64 = since_id.as_ref().and_then(|id| id.parse().ok()).unwrap_or(0);
+        let mut posts: Vec<Post> = posts["posts"].as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Self::post_of)
+            .filter(|post| post.timeline_id.as_ref().and_then(|id| id.parse::<i64>().ok()).unwrap_or(0) > since_id)
+            .collect();
+        posts.sort_by_key(|post| post.timeline_id.as_ref().and_then(|id| id.parse::<i64>().ok()));
+        Ok(posts)
+    }
+
+    // Local posts and comments carry their id in the uri, others are
+    // resolved by the host.
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        let local_id = reqwest::Url::parse(&post.uri).ok()
+            .and_then(|url| {
+                let segments: Vec<String> = url.path_segments()?.map(str::to_string).collect();
+                match segments.as_slice() {
+                    [kind, id] if kind == "post" || kind == "comment" => Some(id.clone()),
+                    _ => None,
+                }
+            });
+        if let Some(id) = local_id {
+            return Ok(id);
+        }
+        let resolved = self.get(format!("https://{}/api/v3/resolve_object?q={}", host, urlencoding::encode(&post.uri)), Operation::Post, client).await?;
+        resolved["post"]["post"]["id"].as_i64()
+            .or_else(|| resolved["comment"]["comment"]["id"].as_i64())
+            .map(|id| id.to_string())
+            .ok_or_else(|| Error::Api(format!("Failed to resolve {} on {}", post.uri, host)))
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        let post = self.get(format!("https://{host}/api/v3/post?id={id}"), Operation::Post, client).await?;
+        Self::post_of(&post["post_view"])
+            .ok_or_else(|| Error::Api(format!("Failed to parse post {id} of {host}")))
+    }
+
+    // Only comments have ancestors: the post and the comments on their path.
+    async fn get_ancestors_of(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let comment = self.get(format!("https://{host}/api/v3/comment?id={id}"), Operation::Context, client).await?;
+        let view = &comment["comment_view"];
+        let root = Self::post_of(view)
+            .ok_or_else(|| Error::Api(format!("Failed to parse post of comment {id} of {host}")))?;
+
+        let mut ancestors = vec![];
+        let path: Vec<&str> = view["comment"]["path"].as_str().unwrap_or_default().split('.').collect();
+        for ancestor_id in path.iter().skip(1).take(path.len().saturating_sub(2)) {
+            let ancestor = self.get(format!("https://{host}/api/v3/comment?id={ancestor_id}"), Operation::Context, client).await?;
+            if let Some(ancestor) = Self::comment_of(&ancestor["comment_view"], &root) {
+                ancestors.push(ancestor);
+            }
+        }
+        ancestors.insert(0, root);
+        Ok(ancestors)
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, _db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        let mut comments = vec![];
+        for page in 1..=MAX_COMMENT_PAGES {
+            let page = self.get(format!("https://{host}/api/v3/comment/list?post_id={id}&sort=Old&type_=All&limit={COMMENTS_LIMIT}&page={page}"), Operation::Context, client).await?;
+            let views = page["comments"].as_array().cloned().unwrap_or_default();
+            comments.extend(views.iter().filter_map(|view| Self::comment_of(view, post)));
+            if views.len() < COMMENTS_LIMIT {
+                break;
+            }
+        }
+        Ok(comments)
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+use futures::{stream::BoxStream, StreamExt};
+use eventsource_stream::Eventsource;
+use axum::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use crate::{db::Database, post::Post, error::Error, request::{self, Operation}};
+use super::Backend;
+
+#[derive(Deserialize)]
+pub(super) struct Context {
+    pub(super) ancestors: Vec<Post>,
+    pub(super) descendants: Vec<Post>,
+}
+
+/// Mastodon and software implementing its client api
+pub struct Mastodon {
+    token: Option<String>,
+}
+
+impl Mastodon {
+    pub fn new(token: Option<String>) -> Self {
+        Mastodon { token }
+    }
+
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(Self::new(token))
+    }
+
+    pub(super) fn auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Looks up the api id of a post known to the host, without fetching it
+    /// from remote.
+    pub(super) async fn search_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        let search_url = format!("https://{}/api/v2/search?q={}&type=statuses&resolve=false&limit=1",
+                                 host, urlencoding::encode(&post.uri));
+        let res = request::send(self.auth(client.get(search_url)), Operation::Post).await?;
+
+        let results: Value = request::json(res).await?;
+        results["statuses"].as_array()
+            .into_iter()
+            .flatten()
+            .find(|status| status["uri"] == post.uri.as_str() || status["url"] == post.uri.as_str())
+            .and_then(|status| status["id"].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| Error::Api(format!("Failed to find {} by search", post.uri)))
+    }
+
+    pub(super) async fn get_status(&self, host: &str, id: &str, client: &Client) -> Result<Post, Error> {
+        let status_url = format!("https://{}/api/v1/statuses/{}", host, id);
+
+        let res = request::send(self.auth(client.get(status_url)), Operation::Post).await?;
+
+        request::json(res).await
+    }
+
+    pub(super) async fn get_context(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Context, Error> {
+        let context_url = format!("https://{}/api/v1/statuses/{}/context", host, id);
+
+        let res = request::send(self.auth(client.get(context_url)), Operation::Context).await?;
+
+        let mut context: Context = request::json(res).await?;
+        // The id of the post may not be its last uri segment, so replies to
+        // it are linked here.
+        for descendant in &mut context.descendants {
+            if descendant.in_reply_to_id.as_deref() == Some(id) {
+                descendant.in_reply_to = Some(post.uri.clone());
+            }
+        }
+        Ok(context)
+    }
+}
+
+#[async_trait]
+impl Backend for Mastodon {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let res = request::send(self.auth(client.get(format!("https://{host}/api/v1/accounts/verify_credentials"))), Operation::Post).await?;
+        let account: Value = request::json(res).await?;
+        // Bit 0 of the role permissions is `administrator`
+        let is_admin = account["role"]["permissions"].as_str()
+            .and_then(|permissions| permissions.parse::<u64>().ok())
+            .map_or(false, |permissions| permissions & 1 != 0);
+        if is_admin {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let trends_url = format!("https://{host}/api/v1/trends/statuses?limit=10");
+        let res = request::send(self.auth(client.get(trends_url)), Operation::Timeline).await?;
+        
+        let posts: Vec<Post> = request::json(res).await?;
+        Ok(posts.into_iter().map(|post| post.origin()).collect())
+    }
+
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        let timeline_url = match since_id {
+            Some(id) => format!("https://{}/api/v1/timelines/public?limit=40&min_id={}", host, id),
+            None => format!("https://{}/api/v1/timelines/public?limit=40", host),
+        };
+        let res = request::send(self.auth(client.get(timeline_url)), Operation::Timeline).await?;
+
+        let mut posts: Vec<Post> = request::json(res).await?;
+        posts.sort_by_key(|p| p.created_at.clone().unwrap());
+        Ok(posts)
+    }
+
+    async fn stream_global_timeline(&self, host: &str, client: &Client) -> Result<BoxStream<'static, Result<Post, Error>>, Error> {
+        let streaming_url = format!("https://{host}/api/v1/streaming/public");
+        // The stream stays open, so only waiting for its headers is bounded
+        let request = self.auth(client.get(&streaming_url))
+            .header("accept", "text/event-stream")
+            .timeout(Duration::MAX)
+            .send();
+        let res = tokio::time::timeout(Operation::Timeline.timeout(), request).await
+            .map_err(|_| Error::Timeout(streaming_url))?
+            .map_err(request::classify)?;
+        if res.status() != StatusCode::OK {
+            return Err(Error::Status(res.url().to_string(), res.status()));
+        }
+
+        let host = host.to_string();
+        let posts = res.bytes_stream()
+            .eventsource()
+            .filter_map(move |event| {
+                let post = match event {
+                    Ok(event) if event.event == "update" => {
+                        match serde_json::from_str::<Post>(&event.data) {
+                            Ok(post) => Some(Ok(post)),
+                            Err(e) => {
+                                tracing::warn!("Failed to parse streamed post from {}: {:?}", host, e);
+                                None
+                            },
+                        }
+                    },
+                    Ok(_) => None,
+                    Err(e) => Some(Err(Error::Stream(format!("{host}: {e}")))),
+                };
+                async move { post }
+            });
+        Ok(posts.boxed())
+    }
+
+    // Local statuses live at `/users/<name>/statuses/<id>`, anything else
+    // is looked up by search.
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        let local_id = reqwest::Url::parse(&post.uri).ok()
+            .and_then(|url| {
+                let segments: Vec<String> = url.path_segments()?.map(str::to_string).collect();
+                match segments.as_slice() {
+                    [users, _, statuses, id] if users == "users" && statuses == "statuses" => Some(id.clone()),
+                    _ => None,
+                }
+            });
+        match local_id {
+            Some(id) => Ok(id),
+            None => self.search_id(host, post, client).await,
+        }
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        self.get_status(host, id, client).await
+    }
+
+    async fn get_ancestors_of(&self, host: &str, post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let context = self.get_context(host, post, id, client).await?;
+        Ok(context.ancestors)
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, _db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        let context = self.get_context(host, post, id, client).await?;
+        Ok(context.descendants)
+    }
+}
@@ -0,0 +1,155 @@
+use axum::async_trait;
+use serde_json::Value;
+use reqwest::{Client, RequestBuilder};
+use crate::{db::Database, post::Post, error::Error, request::{self, Operation}};
+use super::Backend;
+
+const COMMENTS_LIMIT: usize = 100;
+// Upper bound of comment pages fetched per thread
+const MAX_COMMENT_PAGES: usize = 10;
+
+/// kbin and its fork Mbin. Local threads have no `apId`, their uri is
+/// built from the magazine and entry id instead.
+pub struct Mbin {
+    token: Option<String>,
+}
+
+impl Mbin {
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(Mbin { token })
+    }
+
+    fn auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn get(&self, url: String, operation: Operation, client: &Client) -> Result<Value, Error> {
+        let res = request::send(self.auth(client.get(url)), operation).await?;
+        request::json(res).await
+    }
+
+    fn entry_of(host: &str, entry: &Value) -> Option<Post> {
+        let id = entry["entryId"].as_i64()?;
+        let uri = match entry["apId"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => format!("https://{}/m/{}/t/{}", host, entry["magazine"]["name"].as_str()?, id),
+        };
+        let mut post = Post::from_uri(uri);
+        post.timeline_id = Some(id.to_string());
+        post.created_at = entry["createdAt"].as_str().map(str::to_string);
+        post.replies_count = entry["numComments"].as_i64();
+        Some(post)
+    }
+
+    // Comments are nested in `children`, which are flattened with their parents
+    fn collect_comments(host: &str, root: &Post, comment: &Value, parent: Option<&Post>, comments: &mut Vec<Post>) {
+        let Some(id) = comment["commentId"].as_i64() else { return };
+        let uri = match comment["apId"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => match (comment["magazine"]["name"].as_str(), &root.timeline_id) {
+                (Some(magazine), Some(entry_id)) => format!("https://{host}/m/{magazine}/t/{entry_id}/-/comment/{id}"),
+                _ => return,
+            },
+        };
+        let parent = parent.unwrap_or(root);
+        let mut reply = Post::from_uri(uri);
+        reply.timeline_id = Some(id.to_string());
+        reply.created_at = comment["createdAt"].as_str().map(str::to_string);
+        reply.in_reply_to_id = parent.timeline_id.clone();
+        reply.in_reply_to = Some(parent.uri.clone());
+        for child in comment["children"].as_array().into_iter().flatten() {
+            Self::collect_comments(host, root, child, Some(&reply), comments);
+        }
+        comments.push(reply);
+    }
+
+    // Only threads hosted here can be looked up
+    fn entry_id_of(post: &Post) -> Result<String, Error> {
+        reqwest::Url::parse(&post.uri).ok()
+            .and_then(|url| {
+                let segments: Vec<String> = url.path_segments()?.map(str::to_string).collect();
+                match segments.as_slice() {
+                    [m, _, t, id, ..] if m == "m" && t == "t" => Some(id.clone()),
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| Error::Api(format!("Failed to get entry id of {}", post.uri)))
+    }
+}
+
+#[async_trait]
+impl Backend for Mbin {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let user = self.get(format!("https://{host}/api/users/me"), Operation::Post, client).await?;
+        if user["isAdmin"] == true {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let entries = self.get(format!("https://{host}/api/entries?sort=hot&perPage=10"), Operation::Timeline, client).await?;
+        Ok(entries["items"].as_array()
+           .into_iter()
+           .flatten()
+           .filter_map(|entry| Self::entry_of(host, entry))
+           .collect())
+    }
+
+    // There is no cursor, so newer threads are picked from the newest page
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        let entries = self.get(format!("https://{host}/api/entries?sort=newest&federation=local&perPage=50"), Operation::Timeline, client).await?;
+        let since_id: i64 = since_id.as_ref().and_then(|id| id.parse().ok()).unwrap_or(0);
+        let mut posts: Vec<Post> = entries["items"].as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Self::entry_of(host, entry))
+            .filter(|post| post.timeline_id.as_ref().and_then(|id| id.parse::<i64>().ok()).unwrap_or(0) > since_id)
+            .collect();
+        posts.sort_by_key(|post| post.timeline_id.as_ref().and_then(|id| id.parse::<i64>().ok()));
+        Ok(posts)
+    }
+
+    async fn lookup_id(&self, _host: &str, post: &Post, _client: &Client) -> Result<String, Error> {
+        Self::entry_id_of(post)
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        let entry = self.get(format!("https://{host}/api/entry/{id}"), Operation::Post, client).await?;
+        Self::entry_of(host, &entry)
+            .ok_or_else(|| Error::Api(format!("Failed to parse entry {id} of {host}")))
+    }
+
+    // Comments of a thread are only linked through the thread itself, so
+    // the entry is their only known ancestor. The id may be that of the
+    // comment, the entry is taken from its uri instead.
+    async fn get_ancestors_of(&self, host: &str, post: &Post, _id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let is_comment = reqwest::Url::parse(&post.uri).ok()
+            .and_then(|url| url.path_segments().map(|mut segments| segments.any(|segment| segment == "comment")))
+            .unwrap_or(false);
+        if !is_comment {
+            return Err(Error::Api(format!("{} is an entry without ancestors", post.uri)));
+        }
+        let entry_id = Self::entry_id_of(post)?;
+        Ok(vec![self.get_post(host, post, &entry_id, client).await?])
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, _db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        let mut comments = vec![];
+        for page in 1..=MAX_COMMENT_PAGES {
+            let page = self.get(format!("https://{host}/api/entry/{id}/comments?sortBy=oldest&perPage={COMMENTS_LIMIT}&p={page}"), Operation::Context, client).await?;
+            let items = page["items"].as_array().cloned().unwrap_or_default();
+            for comment in &items {
+                Self::collect_comments(host, post, comment, None, &mut comments);
+            }
+            if items.len() < COMMENTS_LIMIT {
+                break;
+            }
+        }
+        Ok(comments)
+    }
+}
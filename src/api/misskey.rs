@@ -0,0 +1,293 @@
+use futures::{stream::{self, BoxStream}, SinkExt, StreamExt};
+use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, Message}};
+use axum::async_trait;
+use serde_json::{json, Value, Map};
+use reqwest::{Client, StatusCode};
+use crate::{db::{ChildrenCursor, Database}, post::Post, error::Error, request::{self, Operation}};
+use super::Backend;
+
+const CHILDREN_LIMIT: usize = 100;
+// Bounds of a single reply tree walk
+const CONCURRENCY: usize = 4;
+const REQUEST_BUDGET: usize = 100;
+const MAX_DEPTH: i32 = 32;
+
+/// Misskey and its forks
+pub struct Misskey {
+    token: Option<String>,
+}
+
+impl Misskey {
+    pub fn boxed(token: Option<String>) -> Box<dyn Backend> {
+        Box::new(Misskey { token })
+    }
+
+    fn auth(&self, mut body: Value) -> Value {
+        if let (Some(token), Value::Object(body)) = (&self.token, &mut body) {
+            body.insert("i".to_string(), Value::String(token.clone()));
+        }
+        body
+    }
+
+    fn supplement_uri(host: &str, post: &mut Map<String, Value>) -> Result<(), Error> {
+        let id_value = post.get("id")
+            .ok_or_else(||Error::Api(format!("Failed to parse response from {host}: missing field `id`")))?
+            .clone();
+        let id = id_value.as_str()
+            .ok_or_else(||Error::Api(format!("Failed to parse response from {host}: `id` is not string")))?;
+        post.entry("uri".to_string()).or_insert(serde_json::to_value(
+                format!("https://{}/notes/{}", host, id))?);
+
+        if let Some(Value::Object(renote)) = post.get_mut("renote") {
+            Self::supplement_uri(host, renote)?;
+        }
+
+        if let Some(Value::Object(reply)) = post.get_mut("reply") {
+            Self::supplement_uri(host, reply)?;
+        }
+
+        Ok(())
+    }
+
+    async fn posts_from_response(host: &str, response: reqwest::Response) -> Result<Vec<Post>, Error> {
+        let mut posts: Vec<Map<String, Value>> = request::json(response).await?;
+        for post in &mut posts {
+            Self::supplement_uri(host, post)?;
+        }
+        let value = serde_json::to_value(posts)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    async fn get_children(&self, host: &str, parent_id: &str, since_id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let children_url = format!("https://{}/api/notes/children", host);
+        let res = request::send(
+            client.post(children_url)
+                .json(&self.auth(json!({ "noteId": parent_id, "sinceId": since_id, "limit": CHILDREN_LIMIT }))),
+            Operation::Context,
+        ).await?;
+
+        let mut children = Self::posts_from_response(host, res).await?;
+        children.sort_by_key(|p| p.created_at.clone().unwrap());
+        Ok(children)
+    }
+}
+
+#[async_trait]
+impl Backend for Misskey {
+    async fn verify_admin_token(&self, host: &str, client: &Client) -> Result<(), Error> {
+        let res = request::send(
+            client.post(format!("https://{host}/api/i"))
+                .json(&self.auth(json!({}))),
+            Operation::Post,
+        ).await?;
+        let account: Value = request::json(res).await?;
+        let is_admin = account["isAdmin"] == true || account["isModerator"] == true;
+        if is_admin {
+            Ok(())
+        } else {
+            Err(Error::Api(format!("Token does not belong to an administrator of {host}")))
+        }
+    }
+
+    async fn get_trending_posts(&self, host: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let trends_url = format!("https://{host}/api/notes/featured");
+        let res = request::send(
+            client.post(trends_url)
+                .json(&self.auth(json!({ "limit": 10 }))),
+            Operation::Timeline,
+        ).await?;
+        
+        let posts = Self::posts_from_response(host, res).await?;
+        Ok(posts.into_iter().map(|post| post.origin()).collect())
+    }
+
+    async fn get_global_timeline(&self, host: &str, since_id: &Option<String>, client: &Client) -> Result<Vec<Post>, Error> {
+        let timeline_url = format!("https://{}/api/notes/global-timeline", host);
+        let body_json = match since_id {
+            Some(id) => json!({ "limit": 100, "sinceId": id }),
+            None     => json!({ "limit": 100 }),
+        };
+
+        let res = request::send(
+            client.post(timeline_url)
+                .json(&self.auth(body_json)),
+            Operation::Timeline,
+        ).await?;
+
+        let mut posts = Self::posts_from_response(host, res).await?;
+        posts.sort_by_key(|p| p.created_at.clone().unwrap());
+        Ok(posts)
+    }
+
+    async fn stream_global_timeline(&self, host: &str, _client: &Client) -> Result<BoxStream<'static, Result<Post, Error>>, Error> {
+        let streaming_url = match &self.token {
+            Some(token) => format!("wss://{}/streaming?i={}", host, urlencoding::encode(token)),
+            None => format!("wss://{host}/streaming"),
+        };
+        let mut request = streaming_url.into_client_request()
+            .map_err(|e| Error::WebSocket(Box::new(e)))?;
+        request.headers_mut().insert("user-agent", http::HeaderValue::from_static(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION"),
+        )));
+        let (mut socket, _) = tokio::time::timeout(Operation::Timeline.timeout(), connect_async(request)).await
+            .map_err(|_| Error::Timeout(format!("wss://{host}/streaming")))?
+            .map_err(|e| Error::WebSocket(Box::new(e)))?;
+        let connect = json!({
+            "type": "connect",
+            "body": { "channel": "globalTimeline", "id": "courier" },
+        });
+        socket.send(Message::Text(connect.to_string())).await
+            .map_err(|e| Error::WebSocket(Box::new(e)))?;
+
+        let host = host.to_string();
+        let posts = socket
+            .filter_map(move |message| {
+                let post = match message {
+                    Ok(Message::Text(text)) => {
+                        let note = serde_json::from_str::<Value>(&text).ok()
+                            .filter(|message| message["type"] == "channel" && message["body"]["type"] == "note")
+                            .and_then(|mut message| match message["body"]["body"].take() {
+                                Value::Object(note) => Some(note),
+                                _ => None,
+                            });
+                        let post = note.map(|mut note| -> Result<Post, Error> {
+                            Self::supplement_uri(&host, &mut note)?;
+                            Ok(serde_json::from_value(Value::Object(note))?)
+                        });
+                        match post {
+                            Some(Ok(post)) => Some(Ok(post)),
+                            Some(Err(e)) => {
+                                tracing::warn!("Failed to parse streamed post from {}: {:?}", host, e);
+                                None
+                            },
+                            None => None,
+                        }
+                    },
+                    Ok(Message::Close(_)) => Some(Err(Error::Stream(format!("{host}: stream closed")))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(Error::WebSocket(Box::new(e)))),
+                };
+                async move { post }
+            });
+        Ok(posts.boxed())
+    }
+
+    // Local notes live at `/notes/<id>`, anything else is looked up with
+    // `ap/show`. It needs a token, fetches objects the host does not know
+    // yet into it, and is limited to about 30 calls per hour.
+    async fn lookup_id(&self, host: &str, post: &Post, client: &Client) -> Result<String, Error> {
+        let local_id = reqwest::Url::parse(&post.uri).ok()
+            .and_then(|url| {
+                let segments: Vec<String> = url.path_segments()?.map(str::to_string).collect();
+                match segments.as_slice() {
+                    [notes, id] if notes == "notes" => Some(id.clone()),
+                    _ => None,
+                }
+            });
+        if let Some(id) = local_id {
+            return Ok(id);
+        }
+        if self.token.is_none() {
+            return Err(Error::Api(format!("Failed to look up {} on {}: no token", post.uri, host)));
+        }
+
+        let res = request::send(
+            client.post(format!("https://{}/api/ap/show", host))
+                .json(&self.auth(json!({ "uri": post.uri }))),
+            Operation::Post,
+        ).await?;
+        match res.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN =>
+                return Err(Error::Api(format!("Failed to look up {} on {}: token rejected", post.uri, host))),
+            StatusCode::TOO_MANY_REQUESTS =>
+                return Err(Error::Api(format!("Failed to look up {} on {}: ap/show rate limit used up", post.uri, host))),
+            _ => {},
+        }
+        let object: Value = request::json(res).await?;
+        match (object["type"].as_str(), object["object"]["id"].as_str()) {
+            (Some("Note"), Some(id)) => Ok(id.to_string()),
+            _ => Err(Error::Api(format!("Failed to look up {}: not a note", post.uri))),
+        }
+    }
+
+    async fn get_post(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Post, Error> {
+        let show_url = format!("https://{}/api/notes/show", host);
+
+        let res = request::send(
+            client.post(show_url)
+                .json(&self.auth(json!({ "noteId": id }))),
+            Operation::Post,
+        ).await?;
+
+        let mut note: Map<String, Value> = request::json(res).await?;
+        Self::supplement_uri(host, &mut note)?;
+        Ok(serde_json::from_value(Value::Object(note))?)
+    }
+
+    async fn get_ancestors_of(&self, host: &str, _post: &Post, id: &str, client: &Client) -> Result<Vec<Post>, Error> {
+        let conversation_url = format!("https://{}/api/notes/conversation", host);
+
+        let res = request::send(
+            client.post(conversation_url)
+                .json(&self.auth(json!({ "noteId": id, "limit": 100 }))),
+            Operation::Context,
+        ).await?;
+
+        // The conversation starts from the parent and ends at the root
+        let mut ancestors = Self::posts_from_response(host, res).await?;
+        ancestors.reverse();
+        Ok(ancestors)
+    }
+
+    async fn get_descendants_of(&self, host: &str, post: &Post, id: &str, db: &Database, client: &Client) -> Result<Vec<Post>, Error> {
+        let mut wave = db.get_children_cursors(&post.uri).await?;
+        if !wave.iter().any(|cursor| cursor.parent_id == id) {
+            wave.insert(0, ChildrenCursor { parent_id: id.to_string(), since_id: None, depth: 0 });
+        }
+        let mut budget = REQUEST_BUDGET;
+        let mut descendants = vec![];
+        while !wave.is_empty() && budget > 0 {
+            wave.truncate(budget);
+            budget -= wave.len();
+            let results: Vec<(ChildrenCursor, Result<Vec<Post>, Error>)> = stream::iter(wave)
+                .map(|cursor| async move {
+                    let since_id = cursor.since_id.as_deref().unwrap_or("0");
+                    let children = self.get_children(host, &cursor.parent_id, since_id, client).await;
+                    (cursor, children)
+                })
+                .buffer_unordered(CONCURRENCY)
+                .collect()
+                .await;
+
+            let mut next = vec![];
+            for (cursor, children) in results {
+                let children = match children {
+                    Ok(children) => children,
+                    Err(e) => {
+                        tracing::warn!("{}: {:?}", post.uri, e);
+                        continue;
+                    },
+                };
+                let newest = children.last().and_then(|child| child.timeline_id.clone());
+                db.update_children_cursor(&post.uri, &cursor.parent_id, &newest, cursor.depth).await?;
+                // A full page means there may be more
+                if children.len() >= CHILDREN_LIMIT {
+                    next.push(ChildrenCursor { since_id: newest, ..cursor.clone() });
+                }
+                for child in children {
+                    if let Some(child_id) = &child.timeline_id {
+                        if cursor.depth + 1 < MAX_DEPTH {
+                            db.add_children_cursor(&post.uri, child_id, cursor.depth + 1).await?;
+                            next.push(ChildrenCursor { parent_id: child_id.clone(), since_id: None, depth: cursor.depth + 1 });
+                        }
+                    }
+                    descendants.push(child);
+                }
+            }
+            wave = next;
+        }
+        Ok(descendants)
+    }
+}
@@ -3,25 +3,53 @@ use tokio::{
     sync::mpsc::Sender,
     time::sleep,
 };
-use crate::{post::Post, actor::{Actor, RemoteActor}, error::Error, db::Database};
+use crate::{post::Post, actor::{Actor, RemoteActor}, error::Error, db::{Database, PendingDescendant}};
 
+// Longest time a reply waits for its parent to be delivered, in seconds
+const MAX_HOLD: i64 = 30 * 60;
+
+// Whether the follower can already show the parent of a reply, so that the
+// reply does not arrive disconnected from its thread.
+fn is_releasable(pending: &PendingDescendant, root: &Post, remote_actor: &RemoteActor, now: i64) -> bool {
+    let Some(parent) = &pending.post.in_reply_to else {
+        return true;
+    };
+    let parent_host = reqwest::Url::parse(parent)
+        .ok()
+        .and_then(|url| url.domain().map(str::to_lowercase));
+    parent == &root.uri
+        || pending.parent_delivered == Some(true)
+        || (parent_host.is_some() && parent_host == remote_actor.host())
+        // The parent may never show up, e.g. when it failed to be fetched
+        || pending.post.fetch_time < now - MAX_HOLD
+}
+
+// Replies are released only once their parent was delivered. Deeper replies
+// therefore go out one level per run, in thread order.
 async fn relay_new_posts(actor: &Arc<Actor>,
                          remote_actor: RemoteActor,
                          db: &Database,
                          tx: &Sender<(Arc<Actor>, Arc<RemoteActor>, Arc<Post>)>,) -> Result<(), Error> {
     let monitoring_posts = db.get_monitoring_posts_of(&remote_actor).await?;
     let remote_actor = Arc::new(remote_actor);
+    let now = chrono::Utc::now().timestamp();
     for (post, update_sequence) in monitoring_posts {
-        let new_posts = db.get_descendants_after(&post, update_sequence).await?;
+        let pending = db.get_pending_descendants(&post, update_sequence, &remote_actor).await?;
         let mut new_update_sequence = update_sequence;
-        for (new_post, sequence) in new_posts {
-            let new_post = Arc::new(new_post);
-            if let Err(e) = tx.send((actor.clone(), remote_actor.clone(), new_post.clone())).await {
+        let mut held = false;
+        for pending in pending {
+            if !is_releasable(&pending, &post, &remote_actor, now) {
+                held = true;
+                continue;
+            }
+            db.add_delivery(&remote_actor, &pending.post).await?;
+            if let Err(e) = tx.send((actor.clone(), remote_actor.clone(), Arc::new(pending.post))).await {
                 tracing::error!("send new posts to {}: {:?}", remote_actor.inbox, e);
                 break;
             }
-            if sequence > new_update_sequence {
-                new_update_sequence = sequence;
+            // Held replies are picked up again from the sequence before them
+            if !held && pending.sequence > new_update_sequence {
+                new_update_sequence = pending.sequence;
             }
         }
         if let Err(e) = db.update_monitoring_post(&remote_actor, &post, new_update_sequence).await {
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
 use serde::Deserialize;
 use sigh::{PrivateKey, PublicKey, Key};
+use crate::actor::{Actor, ActorKind};
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -8,6 +10,49 @@ pub struct Config {
     pub listen_port: u16,
     priv_key_file: String,
     pub_key_file: String,
+    #[serde(default = "default_timeline_max_pages")]
+    pub timeline_max_pages: usize,
+    /// API tokens by instance host, for instances that lock their public endpoints
+    #[serde(default)]
+    pub api_tokens: HashMap<String, String>,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub reply_check: ReplyCheck,
+    #[serde(default)]
+    pub crawl: Crawl,
+    /// Also monitor the threads of posts quoted on followed timelines
+    #[serde(default)]
+    pub monitor_quoted: bool,
+    #[serde(default)]
+    pub unlisted_replies: UnlistedReplies,
+    #[serde(default)]
+    pub requests: Requests,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Retention {
+    /// Threads older than this stop being polled for replies
+    pub poll_days: i64,
+    /// Threads older than this are deleted
+    pub delete_days: i64,
+    /// Number of posts deleted per statement
+    pub delete_batch_size: i64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            poll_days: 7,
+            delete_days: 30,
+            delete_batch_size: 500,
+        }
+    }
+}
+
+fn default_timeline_max_pages() -> usize {
+    10
 }
 
 impl Config {
@@ -32,3 +77,106 @@ impl Config {
             .expect("pub_key")
     }
 }
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ReplyCheck {
+    /// Seconds between checks of an active thread
+    pub min_interval: i64,
+    /// Upper bound of the check interval of a quiet thread, in seconds
+    pub max_interval: i64,
+}
+
+impl Default for ReplyCheck {
+    fn default() -> Self {
+        ReplyCheck {
+            min_interval: 60,
+            max_interval: 6 * 3600,
+        }
+    }
+}
+
+/// Crawling of descendants hosted on other instances than the thread origin
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Crawl {
+    pub enabled: bool,
+    /// Number of hops away from the origin instance
+    pub max_depth: usize,
+    /// Remote descendants followed per hop
+    pub max_breadth: usize,
+    /// Lookups per instance and thread
+    pub per_host_budget: usize,
+}
+
+impl Default for Crawl {
+    fn default() -> Self {
+        Crawl {
+            enabled: false,
+            max_depth: 2,
+            max_breadth: 20,
+            per_host_budget: 5,
+        }
+    }
+}
+
+/// Whether relay actors announce unlisted replies. Actors are named by the
+/// last segment of their uri, i.e. `completion` or the trends instance.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct UnlistedReplies {
+    pub default: bool,
+    pub actors: HashMap<String, bool>,
+}
+
+impl UnlistedReplies {
+    pub fn allowed(&self, actor: &Actor) -> bool {
+        let name = match &actor.kind {
+            ActorKind::CompletionRelay => "completion",
+            ActorKind::TrendsRelay(instance) => instance,
+        };
+        self.actors.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+/// Limits of requests to instance apis. Timeouts are in seconds.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Requests {
+    pub connect_timeout: u64,
+    /// Detecting the software and api host of an instance
+    pub detect_timeout: u64,
+    /// Trends and timeline pages
+    pub timeline_timeout: u64,
+    /// A single post, or the lookup of its api id
+    pub post_timeout: u64,
+    /// The context or replies of a thread
+    pub context_timeout: u64,
+    /// Retries of failed GET requests
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled on every retry
+    pub retry_delay: u64,
+    /// Bytes of a response body
+    pub max_response_size: usize,
+    /// Requests per second to a single host
+    pub per_host_rate: f64,
+    /// Upper bound of the pause of a throttled host, in seconds
+    pub max_pause: u64,
+}
+
+impl Default for Requests {
+    fn default() -> Self {
+        Requests {
+            connect_timeout: 5,
+            detect_timeout: 10,
+            timeline_timeout: 15,
+            post_timeout: 10,
+            context_timeout: 30,
+            retries: 2,
+            retry_delay: 500,
+            max_response_size: 8 * 1024 * 1024,
+            per_host_rate: 5.0,
+            max_pause: 3600,
+        }
+    }
+}
@@ -50,9 +50,109 @@ const CREATE_SCHEMA_COMMANDS: &[&str] = &[
             remote_actor TEXT REFERENCES remote_actors (id) ON DELETE CASCADE,
             latest_id    TEXT,
             PRIMARY KEY (remote_actor)
-        )"
+        )",
+
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS token TEXT",
+    "ALTER TABLE instances ALTER COLUMN api_type DROP NOT NULL",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS software TEXT",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS version TEXT",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS detect_time BIGINT",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS api_host TEXT",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS detect_failure TEXT",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS detect_failures INT NOT NULL DEFAULT 0",
+    "ALTER TABLE instances ADD COLUMN IF NOT EXISTS detect_retry_time BIGINT",
+
+    "ALTER TABLE posts ADD COLUMN IF NOT EXISTS created_at BIGINT",
+    "CREATE INDEX IF NOT EXISTS posts_age ON posts ((COALESCE(created_at, fetch_time)))",
+    "CREATE INDEX IF NOT EXISTS descendants_ancester ON descendants (ancester)",
+    "CREATE INDEX IF NOT EXISTS monitor_uri ON monitor (uri)",
+
+    "ALTER TABLE posts ADD COLUMN IF NOT EXISTS next_check BIGINT NOT NULL DEFAULT 0",
+    "ALTER TABLE posts ADD COLUMN IF NOT EXISTS check_interval BIGINT NOT NULL DEFAULT 0",
+    "CREATE INDEX IF NOT EXISTS posts_next_check ON posts (next_check)",
+
+    "CREATE TABLE IF NOT EXISTS
+        children_cursors (
+            uri        TEXT NOT NULL,
+            parent_id  TEXT NOT NULL,
+            since_id   TEXT,
+            depth      INT NOT NULL,
+            check_time BIGINT NOT NULL DEFAULT 0,
+            PRIMARY KEY (uri, parent_id)
+        )",
+
+    "ALTER TABLE posts ADD COLUMN IF NOT EXISTS replies_count BIGINT",
+    "ALTER TABLE posts ADD COLUMN IF NOT EXISTS context_time BIGINT",
+
+    "ALTER TABLE descendants ADD COLUMN IF NOT EXISTS in_reply_to_id TEXT",
+    "ALTER TABLE descendants ADD COLUMN IF NOT EXISTS visibility TEXT",
+    "ALTER TABLE descendants ADD COLUMN IF NOT EXISTS local_only BOOLEAN NOT NULL DEFAULT FALSE",
+
+    "ALTER TABLE descendants ADD COLUMN IF NOT EXISTS timeline_id TEXT",
+    "ALTER TABLE descendants ADD COLUMN IF NOT EXISTS in_reply_to TEXT",
+    "CREATE TABLE IF NOT EXISTS
+        deliveries (
+            remote_actor TEXT REFERENCES remote_actors (id) ON DELETE CASCADE,
+            uri          TEXT REFERENCES descendants (uri) ON DELETE CASCADE,
+            delivered    BOOLEAN NOT NULL DEFAULT FALSE,
+            UNIQUE (remote_actor, uri)
+        )",
+    "CREATE INDEX IF NOT EXISTS deliveries_uri ON deliveries (uri)",
+
+    "CREATE TABLE IF NOT EXISTS
+        api_ids (
+            host       TEXT NOT NULL,
+            uri        TEXT NOT NULL,
+            api_id     TEXT NOT NULL,
+            fetch_time BIGINT NOT NULL,
+            PRIMARY KEY (host, uri)
+        )",
+    "CREATE INDEX IF NOT EXISTS api_ids_fetch_time ON api_ids (fetch_time)",
+
+    "CREATE TABLE IF NOT EXISTS
+        notes (
+            id         TEXT PRIMARY KEY,
+            note       TEXT NOT NULL,
+            created_at BIGINT NOT NULL
+        )",
+    "CREATE INDEX IF NOT EXISTS notes_created_at ON notes (created_at)",
 ];
 
+/// What is known about an instance
+pub struct Instance {
+    pub api_type: Option<String>,
+    pub token: Option<String>,
+    /// Software name and version reported by NodeInfo
+    pub software: Option<String>,
+    pub version: Option<String>,
+    /// When the api type was last detected
+    pub detect_time: Option<i64>,
+    /// Where the api is served, if not on the host itself
+    pub api_host: Option<String>,
+    /// Why the last detection found no known api, cleared by a successful one
+    pub detect_failure: Option<String>,
+    /// Detection failures in a row
+    pub detect_failures: i32,
+    /// No detection is attempted before this time
+    pub detect_retry_time: Option<i64>,
+}
+
+/// A descendant not yet released to a follower
+pub struct PendingDescendant {
+    pub post: Post,
+    pub sequence: i64,
+    /// Whether the parent was delivered, `None` if it was not released yet
+    pub parent_delivered: Option<bool>,
+}
+
+/// Where to resume fetching the children of a note in a thread
+#[derive(Clone)]
+pub struct ChildrenCursor {
+    pub parent_id: String,
+    pub since_id: Option<String>,
+    pub depth: i32,
+}
+
 #[derive(Clone)]
 pub struct Database {
     inner: Arc<DatabaseInner>,
@@ -63,6 +163,8 @@ struct DatabaseInner {
 
     get_instance: Statement,
     add_instance: Statement,
+    add_instance_failure: Statement,
+    set_instance_token: Statement,
 
     add_remote_actor: Statement,
 
@@ -70,11 +172,31 @@ struct DatabaseInner {
     del_follow: Statement,
     get_all_actors: Statement,
     get_following_remote_actors: Statement,
+    get_all_following_remote_actors: Statement,
 
-    get_all_posts: Statement,
+    get_due_posts: Statement,
+    schedule_post: Statement,
+    set_replies_count: Statement,
     add_post: Statement,
+    prune_posts: Statement,
     add_descendant: Statement,
-    get_descendants_after: Statement,
+    get_pending_descendants: Statement,
+    add_delivery: Statement,
+    set_delivered: Statement,
+    get_ancester: Statement,
+
+    get_children_cursors: Statement,
+    add_children_cursor: Statement,
+    update_children_cursor: Statement,
+    prune_children_cursors: Statement,
+
+    get_api_id: Statement,
+    add_api_id: Statement,
+    prune_api_ids: Statement,
+
+    get_note: Statement,
+    add_note: Statement,
+    prune_notes: Statement,
 
     add_monitoring_post: Statement,
     get_monitoring_posts: Statement,
@@ -102,10 +224,31 @@ impl Database {
                 .unwrap();
         }
 
-        let get_instance = client.prepare("SELECT host, api_type FROM instances WHERE host=$1")
+        let get_instance = client.prepare("SELECT api_type, token, software, version, detect_time, api_host, detect_failure, detect_failures, detect_retry_time FROM instances WHERE host=$1")
             .await
             .unwrap();
-        let add_instance = client.prepare("INSERT INTO instances (host, api_type) VALUES($1, $2) ON CONFLICT DO NOTHING")
+        let add_instance = client.prepare("INSERT INTO instances (host, api_type, software, version, detect_time, api_host) VALUES($1, $2, $3, $4, $5, $6)
+                                           ON CONFLICT (host)
+                                           DO UPDATE SET api_type = EXCLUDED.api_type,
+                                                         software = EXCLUDED.software,
+                                                         version = EXCLUDED.version,
+                                                         detect_time = EXCLUDED.detect_time,
+                                                         api_host = EXCLUDED.api_host,
+                                                         detect_failure = NULL,
+                                                         detect_failures = 0,
+                                                         detect_retry_time = NULL")
+            .await
+            .unwrap();
+        let add_instance_failure = client.prepare("INSERT INTO instances (host, detect_failure, detect_failures, detect_retry_time) VALUES($1, $2, 1, $3)
+                                                   ON CONFLICT (host)
+                                                   DO UPDATE SET detect_failure = EXCLUDED.detect_failure,
+                                                                 detect_failures = instances.detect_failures + 1,
+                                                                 detect_retry_time = EXCLUDED.detect_retry_time")
+            .await
+            .unwrap();
+        let set_instance_token = client.prepare("INSERT INTO instances (host, token) VALUES($1, $2)
+                                                 ON CONFLICT (host)
+                                                 DO UPDATE SET token = EXCLUDED.token")
             .await
             .unwrap();
 
@@ -129,17 +272,122 @@ impl Database {
                                                           WHERE actor=$1")
             .await
             .unwrap();
+        let get_all_following_remote_actors = client.prepare("SELECT DISTINCT id, inbox
+                                                              FROM follows JOIN remote_actors
+                                                              ON follows.remote_actor=remote_actors.id")
+            .await
+            .unwrap();
+
+        // The reply count is only trusted while the last full fetch is recent enough
+        let get_due_posts = client.prepare("SELECT DISTINCT uri, fetch_time,
+                                                   CASE WHEN context_time >= $3 THEN replies_count END
+                                            FROM posts
+                                            WHERE COALESCE(created_at, fetch_time) >= $1
+                                            AND next_check <= $2")
+            .await
+            .unwrap();
+        let schedule_post = client.prepare("UPDATE posts
+                                            SET check_interval = CASE WHEN $2 THEN $3 ELSE LEAST(GREATEST(check_interval * 2, $3), $4) END,
+                                                next_check = $5 + CASE WHEN $2 THEN $3 ELSE LEAST(GREATEST(check_interval * 2, $3), $4) END
+                                            WHERE uri=$1")
+            .await
+            .unwrap();
+        let set_replies_count = client.prepare("UPDATE posts SET replies_count=$2, context_time=$3 WHERE uri=$1")
+            .await
+            .unwrap();
+        let add_post = client.prepare("INSERT INTO posts (uri, fetch_time, created_at) VALUES($1, $2, $3) ON CONFLICT DO NOTHING")
+            .await
+            .unwrap();
+        let prune_posts = client.prepare("DELETE FROM posts WHERE uri IN (
+                                              SELECT uri FROM posts
+                                              WHERE COALESCE(created_at, fetch_time) < $1
+                                              LIMIT $2
+                                          )")
+            .await
+            .unwrap();
+        // Parents known only by their api id are looked up among the
+        // descendants stored before.
+        let add_descendant = client.prepare("INSERT INTO descendants (uri, fetch_time, ancester, in_reply_to_id, visibility, local_only,
+                                                                  timeline_id, in_reply_to)
+                                             VALUES($1, $2, $3, $4, $5, $6, $7,
+                                                    COALESCE($8, (SELECT uri FROM descendants
+                                                                  WHERE ancester=$3 AND timeline_id=$4
+                                                                  LIMIT 1)))
+                                             ON CONFLICT DO NOTHING")
+            .await
+            .unwrap();
+        let get_pending_descendants = client.prepare("SELECT uri, fetch_time, sequence, in_reply_to_id, visibility, local_only, in_reply_to,
+                                                             (SELECT delivered FROM deliveries
+                                                              WHERE remote_actor=$3 AND deliveries.uri=descendants.in_reply_to)
+                                                      FROM descendants
+                                                      WHERE ancester=$1 AND sequence > $2
+                                                      AND NOT EXISTS (SELECT 1 FROM deliveries
+                                                                      WHERE remote_actor=$3 AND deliveries.uri=descendants.uri)
+                                                      ORDER BY sequence")
+            .await
+            .unwrap();
+        let add_delivery = client.prepare("INSERT INTO deliveries (remote_actor, uri) VALUES($1, $2) ON CONFLICT DO NOTHING")
+            .await
+            .unwrap();
+        let set_delivered = client.prepare("UPDATE deliveries SET delivered=TRUE WHERE remote_actor=$1 AND uri=$2")
+            .await
+            .unwrap();
+        let get_ancester = client.prepare("SELECT ancester FROM descendants WHERE uri=$1")
+            .await
+            .unwrap();
+
+        let get_children_cursors = client.prepare("SELECT parent_id, since_id, depth FROM children_cursors
+                                                   WHERE uri=$1
+                                                   ORDER BY check_time")
+            .await
+            .unwrap();
+        let add_children_cursor = client.prepare("INSERT INTO children_cursors (uri, parent_id, depth) VALUES($1, $2, $3)
+                                                  ON CONFLICT DO NOTHING")
+            .await
+            .unwrap();
+        let update_children_cursor = client.prepare("INSERT INTO children_cursors (uri, parent_id, since_id, depth, check_time)
+                                                     VALUES($1, $2, $3, $4, $5)
+                                                     ON CONFLICT (uri, parent_id)
+                                                     DO UPDATE SET since_id = COALESCE(EXCLUDED.since_id, children_cursors.since_id),
+                                                                   check_time = EXCLUDED.check_time")
+            .await
+            .unwrap();
+        let prune_children_cursors = client.prepare("DELETE FROM children_cursors WHERE ctid IN (
+                                                         SELECT ctid FROM children_cursors
+                                                         WHERE check_time < $1
+                                                         LIMIT $2
+                                                     )")
+            .await
+            .unwrap();
 
-        let get_all_posts = client.prepare("SELECT DISTINCT uri, fetch_time FROM posts")
+        let get_api_id = client.prepare("SELECT api_id FROM api_ids WHERE host=$1 AND uri=$2")
             .await
             .unwrap();
-        let add_post = client.prepare("INSERT INTO posts (uri, fetch_time) VALUES($1, $2) ON CONFLICT DO NOTHING")
+        let add_api_id = client.prepare("INSERT INTO api_ids (host, uri, api_id, fetch_time) VALUES($1, $2, $3, $4)
+                                         ON CONFLICT (host, uri)
+                                         DO UPDATE SET api_id = EXCLUDED.api_id, fetch_time = EXCLUDED.fetch_time")
             .await
             .unwrap();
-        let add_descendant = client.prepare("INSERT INTO descendants (uri, fetch_time, ancester) VALUES($1, $2, $3) ON CONFLICT DO NOTHING")
+        let prune_api_ids = client.prepare("DELETE FROM api_ids WHERE ctid IN (
+                                                SELECT ctid FROM api_ids
+                                                WHERE fetch_time < $1
+                                                LIMIT $2
+                                            )")
             .await
             .unwrap();
-        let get_descendants_after = client.prepare("SELECT uri, fetch_time, sequence FROM descendants WHERE ancester=$1 AND sequence > $2")
+
+        let get_note = client.prepare("SELECT note FROM notes WHERE id=$1")
+            .await
+            .unwrap();
+        let add_note = client.prepare("INSERT INTO notes (id, note, created_at) VALUES($1, $2, $3)
+                                       ON CONFLICT (id) DO NOTHING")
+            .await
+            .unwrap();
+        let prune_notes = client.prepare("DELETE FROM notes WHERE ctid IN (
+                                              SELECT ctid FROM notes
+                                              WHERE created_at < $1
+                                              LIMIT $2
+                                          )")
             .await
             .unwrap();
 
@@ -173,14 +421,33 @@ impl Database {
 
                 get_instance,
                 add_instance,
+                add_instance_failure,
+                set_instance_token,
                 add_remote_actor,
                 add_follow,
                 del_follow,
                 get_all_actors,
                 get_following_remote_actors,
-                get_all_posts,
+                get_all_following_remote_actors,
+                get_due_posts,
+                schedule_post,
+                set_replies_count,
                 add_post,
-                get_descendants_after,
+                prune_posts,
+                get_pending_descendants,
+                add_delivery,
+                set_delivered,
+                get_ancester,
+                get_children_cursors,
+                add_children_cursor,
+                update_children_cursor,
+                prune_children_cursors,
+                get_api_id,
+                add_api_id,
+                prune_api_ids,
+                get_note,
+                add_note,
+                prune_notes,
                 add_descendant,
                 add_monitoring_post,
                 get_monitoring_posts,
@@ -191,14 +458,40 @@ impl Database {
         }
     }
 
-    pub async fn get_instance_type(&self, host: &str) -> Result<String, Error> {
-        let row = self.inner.client.query_one(&self.inner.get_instance, &[&host])
+    pub async fn get_instance(&self, host: &str) -> Result<Option<Instance>, Error> {
+        let row = self.inner.client.query_opt(&self.inner.get_instance, &[&host])
+            .await?;
+        Ok(row.map(|row| Instance {
+            api_type: row.get(0),
+            token: row.get(1),
+            software: row.get(2),
+            version: row.get(3),
+            detect_time: row.get(4),
+            api_host: row.get(5),
+            detect_failure: row.get(6),
+            detect_failures: row.get(7),
+            detect_retry_time: row.get(8),
+        }))
+    }
+
+    /// Records the detected api type, software and api host of an instance
+    pub async fn add_instance(&self, host: &str, api_type: &str, software: Option<&str>, version: Option<&str>, api_host: &str) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        self.inner.client.execute(&self.inner.add_instance, &[&host, &api_type, &software, &version, &now, &api_host])
+            .await?;
+        Ok(())
+    }
+
+    /// Records a failed detection, to be retried no earlier than `retry_time`.
+    /// Unreachable hosts have no failure reason.
+    pub async fn add_instance_failure(&self, host: &str, failure: Option<&str>, retry_time: i64) -> Result<(), Error> {
+        self.inner.client.execute(&self.inner.add_instance_failure, &[&host, &failure, &retry_time])
             .await?;
-        Ok(row.get(1))
+        Ok(())
     }
 
-    pub async fn add_instance(&self, host: &str, api_type: &str) -> Result<(), Error> {
-        self.inner.client.execute(&self.inner.add_instance, &[&host, &api_type])
+    pub async fn set_instance_token(&self, host: &str, token: &str) -> Result<(), Error> {
+        self.inner.client.execute(&self.inner.set_instance_token, &[&host, &token])
             .await?;
         Ok(())
     }
@@ -235,47 +528,182 @@ impl Database {
         )
     }
 
-    pub async fn get_all_posts(&self) -> Result<impl Iterator<Item = Post>, Error> {
-        let rows = self.inner.client.query(&self.inner.get_all_posts, &[])
+    pub async fn get_all_following_remote_actors(&self) -> Result<impl Iterator<Item = RemoteActor>, Error> {
+        let rows = self.inner.client.query(&self.inner.get_all_following_remote_actors, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| RemoteActor {
+               id: row.get(0),
+               inbox: row.get(1)
+           })
+        )
+    }
+
+    /// Returns posts created (or, if unknown, fetched) since the given time
+    /// that are due for a check of their descendants.
+    /// Due posts carry their last known reply count if their context was
+    /// fully fetched after `context_since`
+    pub async fn get_due_posts(&self, since: i64, now: i64, context_since: i64) -> Result<impl Iterator<Item = Post>, Error> {
+        let rows = self.inner.client.query(&self.inner.get_due_posts, &[&since, &now, &context_since])
             .await?;
         Ok(rows.into_iter()
            .map(|row| Post {
-               uri: row.get(0),
                fetch_time: row.get(1),
-               timeline_id: None,
-               created_at: None,
-               in_reply_to_id: None,
-               reblog: None,
+               replies_count: row.get(2),
+               ..Post::from_uri(row.get(0))
            }))
     }
 
-    pub async fn insert_descendants(&self, post: &Post, descendants: impl Iterator<Item = Post>) -> Result<(), Error> {
+    /// Schedules the next check of a post. The check interval is reset to
+    /// `min_interval` when the thread is active, and doubled up to
+    /// `max_interval` otherwise.
+    pub async fn schedule_post(&self, post: &Post, active: bool, min_interval: i64, max_interval: i64) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        self.inner.client.execute(&self.inner.schedule_post, &[&post.uri, &active, &min_interval, &max_interval, &now])
+            .await?;
+        Ok(())
+    }
+
+    /// Records the reply count seen with a full fetch of the context
+    pub async fn set_replies_count(&self, post: &Post, replies_count: Option<i64>) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        self.inner.client.execute(&self.inner.set_replies_count, &[&post.uri, &replies_count, &now])
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the number of descendants that were not known before
+    pub async fn insert_descendants(&self, post: &Post, descendants: impl Iterator<Item = Post>) -> Result<u64, Error> {
         let mut tasks = vec![];
         for descendant in descendants {
             tasks.push(async move {
                     self.inner.client
                         .execute(&self.inner.add_descendant,
-                                 &[&descendant.uri, &descendant.fetch_time, &post.uri])
+                                 &[&descendant.uri, &descendant.fetch_time, &post.uri,
+                                   &descendant.in_reply_to_id, &descendant.visibility, &descendant.local_only,
+                                   &descendant.timeline_id, &descendant.in_reply_to])
                         .await
                 }
             );
         }
-        join_all(tasks).await.into_iter().collect::<Result<Vec<u64>, Error>>()?;
+        let inserted = join_all(tasks).await.into_iter().collect::<Result<Vec<u64>, Error>>()?;
+        Ok(inserted.into_iter().sum())
+    }
+
+    /// Returns the descendants of a post after `sequence` that were not
+    /// released to the remote actor yet, in insertion order
+    pub async fn get_pending_descendants(&self, post: &Post, sequence: i64, remote_actor: &RemoteActor) -> Result<impl Iterator<Item = PendingDescendant>, Error> {
+        let rows = self.inner.client.query(&self.inner.get_pending_descendants, &[&post.uri, &sequence, &remote_actor.id])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| PendingDescendant {
+               post: Post {
+                   fetch_time: row.get(1),
+                   in_reply_to_id: row.get(3),
+                   visibility: row.get(4),
+                   local_only: row.get(5),
+                   in_reply_to: row.get(6),
+                   ..Post::from_uri(row.get(0))
+               },
+               sequence: row.get(2),
+               parent_delivered: row.get(7),
+           }))
+    }
+
+    /// Records that a descendant was handed to the relay for a remote actor
+    pub async fn add_delivery(&self, remote_actor: &RemoteActor, post: &Post) -> Result<(), Error> {
+        self.inner.client.execute(&self.inner.add_delivery, &[&remote_actor.id, &post.uri])
+            .await?;
         Ok(())
     }
 
-    pub async fn get_descendants_after(&self, post: &Post, sequence: i64) -> Result<impl Iterator<Item = (Post, i64)>, Error> {
-        let rows = self.inner.client.query(&self.inner.get_descendants_after, &[&post.uri, &sequence])
+    /// Records that the relay delivered a descendant to a remote actor
+    pub async fn set_delivered(&self, remote_actor: &RemoteActor, uri: &str) -> Result<(), Error> {
+        self.inner.client.execute(&self.inner.set_delivered, &[&remote_actor.id, &uri])
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the uri of the monitored post a known descendant belongs to
+    pub async fn get_ancester_of(&self, uri: &str) -> Result<Option<String>, Error> {
+        let row = self.inner.client.query_opt(&self.inner.get_ancester, &[&uri])
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    pub async fn get_children_cursors(&self, uri: &str) -> Result<Vec<ChildrenCursor>, Error> {
+        let rows = self.inner.client.query(&self.inner.get_children_cursors, &[&uri])
             .await?;
         Ok(rows.into_iter()
-           .map(|row| (Post {
-               uri: row.get(0),
-               fetch_time: row.get(1),
-               timeline_id: None,
-               created_at: None,
-               in_reply_to_id: None,
-               reblog: None,
-           }, row.get(2))))
+           .map(|row| ChildrenCursor {
+               parent_id: row.get(0),
+               since_id: row.get(1),
+               depth: row.get(2),
+           })
+           .collect())
+    }
+
+    pub async fn add_children_cursor(&self, uri: &str, parent_id: &str, depth: i32) -> Result<(), Error> {
+        self.inner.client.execute(&self.inner.add_children_cursor, &[&uri, &parent_id, &depth])
+            .await?;
+        Ok(())
+    }
+
+    /// Records a check of the children of `parent_id`, and the newest child if any
+    pub async fn update_children_cursor(&self, uri: &str, parent_id: &str, since_id: &Option<String>, depth: i32) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        self.inner.client.execute(&self.inner.update_children_cursor, &[&uri, &parent_id, since_id, &depth, &now])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes at most `limit` cursors not checked since the given time
+    pub async fn prune_children_cursors(&self, before: i64, limit: i64) -> Result<u64, Error> {
+        self.inner.client.execute(&self.inner.prune_children_cursors, &[&before, &limit])
+            .await
+    }
+
+    /// Returns the api id of a post on the given host
+    pub async fn get_api_id(&self, host: &str, uri: &str) -> Result<Option<String>, Error> {
+        let row = self.inner.client.query_opt(&self.inner.get_api_id, &[&host, &uri])
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Records the api ids of posts on the given host
+    pub async fn add_api_ids(&self, host: &str, ids: impl Iterator<Item = (String, String)>) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        let tasks = ids.map(|(uri, api_id)| async move {
+            self.inner.client.execute(&self.inner.add_api_id, &[&host, &uri, &api_id, &now]).await
+        });
+        join_all(tasks).await.into_iter().collect::<Result<Vec<u64>, Error>>()?;
+        Ok(())
+    }
+
+    /// Deletes at most `limit` api ids recorded before the given time
+    pub async fn prune_api_ids(&self, before: i64, limit: i64) -> Result<u64, Error> {
+        self.inner.client.execute(&self.inner.prune_api_ids, &[&before, &limit])
+            .await
+    }
+
+    /// A note published by the relay, as JSON
+    pub async fn get_note(&self, id: &str) -> Result<Option<String>, Error> {
+        let row = self.inner.client.query_opt(&self.inner.get_note, &[&id])
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    pub async fn add_note(&self, id: &str, note: &str) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        self.inner.client.execute(&self.inner.add_note, &[&id, &note, &now])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes at most `limit` notes published before the given time
+    pub async fn prune_notes(&self, before: i64, limit: i64) -> Result<u64, Error> {
+        self.inner.client.execute(&self.inner.prune_notes, &[&before, &limit])
+            .await
     }
 
     pub async fn get_monitoring_posts_of(&self, remote_actor: &RemoteActor) -> Result<impl Iterator<Item = (Post, i64)>, Error> {
@@ -283,12 +711,8 @@ impl Database {
             .await?;
         Ok(rows.into_iter()
            .map(|row| (Post {
-               uri: row.get(0),
                fetch_time: row.get(1),
-               timeline_id: None,
-               created_at: None,
-               in_reply_to_id: None,
-               reblog: None,
+               ..Post::from_uri(row.get(0))
            }, row.get(2))))
     }
     
@@ -302,7 +726,7 @@ impl Database {
         let mut tasks = vec![];
         for post in posts {
             tasks.push(async move {
-                self.inner.client.execute(&self.inner.add_post, &[&post.uri, &post.fetch_time]).await?;
+                self.inner.client.execute(&self.inner.add_post, &[&post.uri, &post.fetch_time, &post.created_time()]).await?;
                 self.inner.client.execute(&self.inner.add_monitoring_post, &[&remote_actor.id, &post.uri]).await
             });
         }
@@ -310,6 +734,20 @@ impl Database {
         Ok(())
     }
 
+    /// Deletes at most `limit` posts older than the given time, along with
+    /// their descendants and monitors.
+    pub async fn prune_posts(&self, before: i64, limit: i64) -> Result<u64, Error> {
+        self.inner.client.execute(&self.inner.prune_posts, &[&before, &limit])
+            .await
+    }
+
+    /// Returns whether the post was not monitored for the remote actor before
+    pub async fn monitor_post(&self, remote_actor: &RemoteActor, post: &Post) -> Result<bool, Error> {
+        self.inner.client.execute(&self.inner.add_post, &[&post.uri, &post.fetch_time, &post.created_time()]).await?;
+        let inserted = self.inner.client.execute(&self.inner.add_monitoring_post, &[&remote_actor.id, &post.uri]).await?;
+        Ok(inserted > 0)
+    }
+
     pub async fn get_latest_id_of(&self, remote_actor: &RemoteActor) -> Option<String> {
         match self.inner.client.query_one(&self.inner.get_latest_id, &[&remote_actor.id]).await {
             Ok(row) => Some(row.get(0)),
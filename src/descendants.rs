@@ -1,40 +1,147 @@
-use std::{time::Duration, collections::HashMap, sync::Arc};
+use std::{time::Duration, collections::{HashMap, HashSet}, sync::{Arc, Mutex}};
 use tokio::{
-    sync::mpsc::{channel, Sender},
+    sync::mpsc::{channel, error::TrySendError, Sender},
     time::sleep,
 };
 use reqwest::Client;
-use crate::{post::Post, api::FediApi, error::Error, db::Database};
+use sigh::PrivateKey;
+use crate::{post::Post, api::FediApi, config::{Crawl, Retention, ReplyCheck}, error::Error, db::Database, replies};
 
-async fn update_post(post: &Post, api: &FediApi, db: &Database, client: &Client) -> Result<(), Error> {
-    let descendants = api.get_descendants_of(post, client).await?;
-    db.insert_descendants(post, descendants.into_iter()).await?;
-    Ok(())
+pub struct Options {
+    /// Key used to fetch `replies` collections
+    pub key_id: String,
+    pub private_key: PrivateKey,
+    pub retention: Retention,
+    pub reply_check: ReplyCheck,
+    pub crawl: Crawl,
+}
+
+// Falls back to the `replies` collection for unknown software or when the
+// api fails.
+async fn get_descendants(post: &Post, api: &Result<FediApi, Error>, options: &Options, client: &Client) -> Result<Vec<Post>, Error> {
+    if let Ok(api) = api {
+        match api.get_descendants_of(post, client).await {
+            Ok(descendants) => return Ok(descendants),
+            Err(e) => tracing::warn!("descendants: get {} through api, falling back to replies collection: {:?}", post.uri, e),
+        }
+    }
+    replies::get_descendants_of(post, &options.key_id, &options.private_key).await
+}
+
+/// Follows descendants hosted on other instances, which may know replies
+/// that never reached the origin, and merges what they know.
+async fn crawl_remote(post: &Post, mut descendants: Vec<Post>, options: &Options, db: &Database, client: &Client) -> Vec<Post> {
+    let crawl = &options.crawl;
+    let mut seen: HashSet<String> = descendants.iter().map(|descendant| descendant.uri.clone()).collect();
+    seen.insert(post.uri.clone());
+    let origin = post.host();
+    let mut frontier: Vec<Post> = descendants.iter()
+        .filter(|descendant| descendant.host() != origin)
+        .cloned()
+        .collect();
+    let mut budgets: HashMap<String, usize> = HashMap::new();
+    let mut apis: HashMap<String, Result<FediApi, Error>> = HashMap::new();
+
+    for _ in 0..crawl.max_depth {
+        let mut next = vec![];
+        for remote in frontier.into_iter().take(crawl.max_breadth) {
+            let Some(host) = remote.host() else { continue };
+            let budget = budgets.entry(host.clone()).or_insert(crawl.per_host_budget);
+            if *budget == 0 {
+                continue;
+            }
+            *budget -= 1;
+            if !apis.contains_key(&host) {
+                apis.insert(host.clone(), FediApi::from_host(&host, db, client).await);
+            }
+            match get_descendants(&remote, &apis[&host], options, client).await {
+                Ok(found) => {
+                    for descendant in found {
+                        if seen.insert(descendant.uri.clone()) {
+                            if descendant.host().as_ref() != Some(&host) {
+                                next.push(descendant.clone());
+                            }
+                            descendants.push(descendant);
+                        }
+                    }
+                },
+                Err(e) => tracing::warn!("descendants: crawl {}: {:?}", remote.uri, e),
+            }
+        }
+        frontier = next;
+    }
+    descendants
 }
 
-fn spawn_worker(host: String, db: Database, client: Arc<Client>) -> Sender<Post> {
+// Asks for the current reply count of a post, which is all it takes to tell
+// that nothing was added to a thread.
+async fn get_replies_count(post: &Post, api: &Result<FediApi, Error>, client: &Client) -> Option<i64> {
+    let api = api.as_ref().ok()?;
+    match api.get_post(post, client).await {
+        Ok(current) => current.replies_count,
+        Err(e) => {
+            tracing::warn!("descendants: get reply count of {}: {:?}", post.uri, e);
+            None
+        },
+    }
+}
+
+// Returns whether new descendants were found
+async fn update_post(post: &Post, api: &Result<FediApi, Error>, options: &Options, db: &Database, client: &Client) -> Result<bool, Error> {
+    let replies_count = get_replies_count(post, api, client).await;
+    if replies_count.is_some() && replies_count == post.replies_count {
+        return Ok(false);
+    }
+    let mut descendants = get_descendants(post, api, options, client).await?;
+    if options.crawl.enabled {
+        descendants = crawl_remote(post, descendants, options, db, client).await;
+    }
+    let inserted = db.insert_descendants(post, descendants.into_iter()).await?;
+    db.set_replies_count(post, replies_count).await?;
+    Ok(inserted > 0)
+}
+
+async fn schedule_post(post: &Post, active: bool, db: &Database, reply_check: &ReplyCheck) {
+    if let Err(e) = db.schedule_post(post, active, reply_check.min_interval, reply_check.max_interval).await {
+        tracing::error!("descendants: schedule {}: {:?}", post.uri, e);
+    }
+}
+
+// Posts handed to a worker and not yet rescheduled, which are not due again
+type InFlight = Arc<Mutex<HashSet<String>>>;
+
+fn spawn_worker(host: String, db: Database, client: Arc<Client>, options: Arc<Options>, in_flight: InFlight) -> Sender<Post> {
     let (tx, mut rx) = channel(16);
     tokio::spawn(async move {
-        match FediApi::from_host(&host, &db, &client).await {
-            Ok(api) => {
-                while let Some(post) = rx.recv().await {
-                    if let Err(e) = update_post(&post, &api, &db, &client).await {
-                        tracing::error!("descendants: update {}: {:?}", post.uri, e);
-                    }
-                }
-            },
-            Err(e) => {
-                while let Some(post) = rx.recv().await {
-                    tracing::error!("Failed to get api of {}: {:?}", post.uri, e);
-                }
-            },
-        };
+        while let Some(post) = rx.recv().await {
+            // Resolved per post, so that re-detections and retries of failed
+            // detections take effect. Usually this is just a lookup.
+            let api = FediApi::from_host(&host, &db, &client).await;
+            if let Err(e) = &api {
+                tracing::warn!("descendants: get api of {}, using replies collection: {:?}", host, e);
+            }
+            let active = match update_post(&post, &api, &options, &db, &client).await {
+                Ok(active) => active,
+                Err(e) => {
+                    tracing::error!("descendants: update {}: {:?}", post.uri, e);
+                    false
+                },
+            };
+            schedule_post(&post, active, &db, &options.reply_check).await;
+            in_flight.lock().unwrap().remove(&post.uri);
+        }
     });
     tx
 }
 
-async fn update(db: &Database, workers: &mut HashMap<String, Sender<Post>>, client: &Arc<Client>) -> Result<(), Error> {
-    let posts = db.get_all_posts().await?;
+// Posts of a host whose worker is busy are left for the next update, so
+// that a slow host holds up neither other hosts nor fetches a post twice.
+async fn update(db: &Database, workers: &mut HashMap<String, Sender<Post>>, in_flight: &InFlight, client: &Arc<Client>, options: &Arc<Options>) -> Result<(), Error> {
+    let now = chrono::Utc::now().timestamp();
+    // Reply counts only cover direct replies, so the full context is still
+    // fetched at the longest interval to find replies deeper in the thread.
+    let context_since = now - options.reply_check.max_interval;
+    let posts = db.get_due_posts(now - options.retention.poll_days * 86400, now, context_since).await?;
     for post in posts {
         let host = match post.host() {
             Some(host_str) => host_str,
@@ -43,21 +150,34 @@ async fn update(db: &Database, workers: &mut HashMap<String, Sender<Post>>, clie
                 continue;
             }
         };
+        if !in_flight.lock().unwrap().insert(post.uri.clone()) {
+            continue;
+        }
         let tx = workers.entry(host.clone())
-                .or_insert_with(|| spawn_worker(host, db.clone(), client.clone()));
-        if let Err(e) = tx.send(post).await {
-            tracing::error!("descendants: send post to worker: {:?}", e);
+                .or_insert_with(|| spawn_worker(host.clone(), db.clone(), client.clone(), options.clone(), in_flight.clone()));
+        if let Err(e) = tx.try_send(post) {
+            let post = match e {
+                TrySendError::Full(post) => post,
+                TrySendError::Closed(post) => {
+                    tracing::error!("descendants: worker of {} stopped", host);
+                    workers.remove(&host);
+                    post
+                },
+            };
+            in_flight.lock().unwrap().remove(&post.uri);
         }
     }
     Ok(())
 }
 
 
-pub fn spawn(db: Database, client: Arc<Client>) {
+pub fn spawn(db: Database, client: Arc<Client>, options: Options) {
+    let options = Arc::new(options);
     tokio::spawn(async move {
         let mut workers = HashMap::new();
+        let in_flight = InFlight::default();
         loop {
-            if let Err(e) = update(&db, &mut workers, &client).await {
+            if let Err(e) = update(&db, &mut workers, &in_flight, &client, &options).await {
                 tracing::error!("descendants: {:?}", e);
             }
             sleep(Duration::from_secs(60)).await;
@@ -49,8 +49,8 @@ where
             } else {
                 return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "No content-type".to_string()));
             };
-        if ! content_type.starts_with("application/json") &&
-            ! (content_type.starts_with("application/") && content_type.ends_with("+json"))
+        if ! (content_type.starts_with("application/json") ||
+              (content_type.starts_with("application/") && content_type.ends_with("+json")))
         {
             return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Invalid content-type".to_string()));
         }
@@ -104,12 +104,11 @@ impl<'a> Endpoint<'a> {
     /// Validates the requesting actor
     pub async fn remote_actor(
         &self,
-        client: &reqwest::Client,
         key_id: &str,
         private_key: &PrivateKey,
     ) -> Result<Actor, Error> {
         let remote_actor: Actor = serde_json::from_value(
-            authorized_fetch(client, &self.remote_actor_uri, key_id, private_key).await?
+            authorized_fetch(&self.remote_actor_uri, key_id, private_key).await?
         )?;
         let public_key = PublicKey::from_pem(remote_actor.public_key.pem.as_bytes())?;
         if ! (self.signature.verify(&public_key)?) {
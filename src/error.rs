@@ -20,4 +20,24 @@ pub enum Error {
     Response(String),
     #[error("Api error: {:?}", .0)]
     Api(String),
+    #[error("Stream error: {:?}", .0)]
+    Stream(String),
+    #[error("Timed out: {:?}", .0)]
+    Timeout(String),
+    #[error("Failed to connect: {:?}", .0)]
+    Connect(String),
+    #[error("HTTP status {} from {:?}", .1, .0)]
+    Status(String, reqwest::StatusCode),
+    #[error("{:?} is throttled for {} more seconds", .0, .1)]
+    Throttled(String, u64),
+    #[error("Response from {:?} exceeds {} bytes", .0, .1)]
+    TooLarge(String, usize),
+    #[error("{:?} is not on a public https host", .0)]
+    NotPublic(String),
+    #[error("{:?} was unreachable, detection is retried in {} seconds", .0, .1)]
+    DetectPending(String, u64),
+    #[error("{:?} is not supported: {}", .0, .1)]
+    Unsupported(String, String),
+    #[error("WebSocket error")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 }
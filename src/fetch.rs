@@ -1,10 +1,67 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr}, sync::Mutex};
 use http::StatusCode;
 use serde::de::DeserializeOwned;
 use sigh::{PrivateKey, SigningConfig, alg::RsaSha256};
-use crate::{digest, error::Error};
+use reqwest::{redirect::Policy, Client, Url};
+use crate::{digest, error::Error, request::{self, Operation}};
 
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/// Sets the client of signed fetches, once at startup. It must not follow
+/// redirects, which could lead past the address check.
+pub fn configure(client: Client) {
+    if CLIENT.lock().unwrap().replace(client).is_some() {
+        tracing::warn!("fetch client is already configured");
+    }
+}
+
+fn client() -> Client {
+    CLIENT.lock().unwrap()
+        .get_or_insert_with(|| Client::builder().redirect(Policy::none()).build().unwrap())
+        .clone()
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 100.64.0.0/10 is shared address space of carrier-grade NAT
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+      || ip.is_broadcast() || ip.is_documentation() || (a == 100 && (b & 0xc0) == 64))
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // Unique local fc00::/7 and link local fe80::/10
+    !(ip.is_loopback() || ip.is_unspecified() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+        && ip.to_ipv4_mapped().map_or(true, |ip| is_public_ipv4(&ip))
+}
+
+// Uris to fetch come from remote objects, so they must not lead into the
+// network of the relay itself: every address of the host has to be public.
+async fn check_public(url: &Url) -> Result<(), Error> {
+    let host = url.host_str().ok_or(Error::InvalidUri)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if url.scheme() != "https" {
+        return Err(Error::NotPublic(url.to_string()));
+    }
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(443)))
+        .await
+        .map_err(|e| Error::Connect(format!("{url}: {e}")))?
+        .map(|addr| addr.ip())
+        .collect();
+    let is_public = |ip: &IpAddr| match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    };
+    if addrs.is_empty() || !addrs.iter().all(is_public) {
+        return Err(Error::NotPublic(url.to_string()));
+    }
+    Ok(())
+}
+
+/// Fetches an ActivityPub object with a signed GET, in the turn of its host
+/// and within the timeout and size limit of api requests. Only public https
+/// hosts are fetched from, and redirects are not followed.
 pub async fn authorized_fetch<T>(
-    client: &reqwest::Client,
     uri: &str,
     key_id: &str,
     private_key: &PrivateKey,
@@ -12,9 +69,10 @@ pub async fn authorized_fetch<T>(
 where
     T: DeserializeOwned,
 {
-    let url = reqwest::Url::parse(uri)
+    let url = Url::parse(uri)
         .map_err(|_| Error::InvalidUri)?;
     let host = format!("{}", url.host().ok_or(Error::InvalidUri)?);
+    check_public(&url).await?;
     let digest_header = digest::generate_header(&[])
         .expect("digest::generate_header");
     let mut req = http::Request::builder()
@@ -28,12 +86,17 @@ where
         .body(vec![])?;
     SigningConfig::new(RsaSha256, private_key, key_id)
         .sign(&mut req)?;
-    let req: reqwest::Request = req.try_into()?;
-    let res = client.execute(req)
-        .await?;
+    let mut req: reqwest::Request = req.try_into()?;
+    *req.timeout_mut() = Some(Operation::Post.timeout());
+    let host = host.to_lowercase();
+    request::wait_turn(&host, Operation::Post.timeout()).await?;
+    let res = client().execute(req)
+        .await
+        .map_err(request::classify)?;
+    request::observe(&host, &res);
     if res.status() >= StatusCode::OK && res.status() < StatusCode::MULTIPLE_CHOICES {
-        Ok(res.json().await?)
+        Ok(serde_json::from_slice(&request::bytes(res).await?)?)
     } else {
-        Err(Error::Response(res.text().await?))
+        Err(Error::Response(request::text(res).await?))
     }
 }
@@ -1,10 +1,11 @@
 use axum::{
-    extract::{FromRef, Path},
+    extract::{FromRef, Path, Query},
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::get, Router,
+    routing::{get, post}, Json, Router,
 };
 use axum_extra::routing::SpaRouter;
+use serde::Deserialize;
 use serde_json::json;
 use sigh::{PrivateKey, PublicKey};
 use std::{net::SocketAddr, sync::Arc, time::Duration};
@@ -14,6 +15,7 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 mod error;
 mod config;
 mod actor;
+mod ancestors;
 mod api;
 mod db;
 mod digest;
@@ -25,6 +27,10 @@ mod trends;
 mod timeline;
 mod completion;
 mod descendants;
+mod replies;
+mod mention;
+mod request;
+mod retention;
 mod activitypub;
 mod endpoint;
 
@@ -36,6 +42,7 @@ struct State {
     hostname: Arc<String>,
     priv_key: PrivateKey,
     pub_key: PublicKey,
+    ancestors: ancestors::Ancestors,
 }
 
 
@@ -64,8 +71,12 @@ async fn get_trends_actor(
         host: state.hostname.clone(),
         kind: actor::ActorKind::TrendsRelay(instance.to_lowercase()),
     };
-    target.as_activitypub(&state.pub_key)
-        .into_response()
+    let mut actor = target.as_activitypub(&state.pub_key);
+    match api::trends_problem(&instance.to_lowercase(), &state.database).await {
+        Ok(problem) => actor.summary = problem.map(|problem| format!("Trends of {instance} are unavailable: {problem}")),
+        Err(e) => tracing::error!("trends_problem: {}", e),
+    }
+    actor.into_response()
 }
 
 async fn post_completion_relay(
@@ -91,12 +102,161 @@ async fn post_trends_relay(
     post_relay(state, endpoint, target).await
 }
 
+#[derive(Deserialize)]
+struct WebfingerQuery {
+    resource: String,
+}
+
+/// Resolves `@courier-completion@host` style handles, so that users can
+/// mention the actors.
+async fn get_webfinger(
+    axum::extract::State(state): axum::extract::State<State>,
+    Query(query): Query<WebfingerQuery>,
+) -> Response {
+    let account = query.resource.strip_prefix("acct:").unwrap_or(&query.resource);
+    let Some((username, host)) = account.split_once('@') else {
+        return (StatusCode::BAD_REQUEST, "Bad resource").into_response();
+    };
+    if host != state.hostname.as_str() {
+        return (StatusCode::NOT_FOUND, "Unknown host").into_response();
+    }
+    let kind = match username.strip_prefix("courier-") {
+        Some("completion") => actor::ActorKind::CompletionRelay,
+        Some(instance) => actor::ActorKind::TrendsRelay(instance.to_lowercase()),
+        None => return (StatusCode::NOT_FOUND, "Unknown actor").into_response(),
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    ([("content-type", "application/jrd+json")],
+     Json(json!({
+         "subject": format!("acct:{account}"),
+         "links": [{
+             "rel": "self",
+             "type": "application/activity+json",
+             "href": target.uri(),
+         }],
+     }))
+    ).into_response()
+}
+
+// The `Create` activity of a note replied by the relay
+async fn get_note_activity(state: &State, id: &str) -> Result<serde_json::Value, Response> {
+    let note_id = format!("https://{}/note/{}", state.hostname, id);
+    match state.database.get_note(&note_id).await {
+        Ok(Some(activity)) => serde_json::from_str(&activity)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response()),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Unknown note").into_response()),
+        Err(e) => {
+            tracing::error!("get_note: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response())
+        },
+    }
+}
+
+async fn get_note(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(id): Path<String>,
+) -> Response {
+    match get_note_activity(&state, &id).await {
+        Ok(mut activity) => {
+            let mut note = activity["object"].take();
+            note["@context"] = activity["@context"].take();
+            ([("content-type", "application/activity+json")],
+             Json(note)).into_response()
+        },
+        Err(response) => response,
+    }
+}
+
+async fn get_note_create(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(id): Path<String>,
+) -> Response {
+    match get_note_activity(&state, &id).await {
+        Ok(activity) => ([("content-type", "application/activity+json")],
+                         Json(activity)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Hosts that requests are currently held back from
+async fn get_host_backoffs() -> Json<Vec<request::Backoff>> {
+    Json(request::backoffs())
+}
+
+#[derive(Deserialize)]
+struct TokenRegistration {
+    token: String,
+}
+
+/// Lets the admin of a following instance register an API token for it.
+async fn post_instance_token(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(host): Path<String>,
+    Json(registration): Json<TokenRegistration>,
+) -> Response {
+    let host = host.to_lowercase();
+    let following = match state.database.get_all_following_remote_actors().await {
+        Ok(mut remote_actors) => remote_actors.any(|remote_actor| remote_actor.host().as_deref() == Some(&host)),
+        Err(e) => {
+            tracing::error!("get_all_following_remote_actors: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("{}", e)
+            ).into_response();
+        }
+    };
+    if !following {
+        return (StatusCode::FORBIDDEN, "Instance is not following").into_response();
+    }
+
+    let api = match api::FediApi::from_host(&host, &state.database, &state.client).await {
+        Ok(api) => api.with_token(registration.token.clone()),
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST,
+                    format!("Bad instance: {:?}", e)
+            ).into_response();
+        }
+    };
+    if let Err(e) = api.verify_admin_token(&state.client).await {
+        return (StatusCode::FORBIDDEN,
+                format!("Bad token: {:?}", e)
+        ).into_response();
+    }
+
+    match state.database.set_instance_token(&host, &registration.token).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::error!("set_instance_token: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR,
+             format!("{}", e)
+            ).into_response()
+        }
+    }
+}
+
+// Follows of a trends relay are rejected when its host has no trends to
+// relay. A host that is merely unreachable is no reason to reject.
+async fn rejection_of(state: &State, target: &actor::Actor) -> Option<String> {
+    let actor::ActorKind::TrendsRelay(instance) = &target.kind else { return None };
+    let problem = match api::FediApi::from_host(instance, &state.database, &state.client).await {
+        Ok(_) => api::trends_problem(instance, &state.database).await,
+        Err(e @ error::Error::Unsupported(..)) => Ok(Some(e.to_string())),
+        Err(e) => Err(e),
+    };
+    problem.unwrap_or_else(|e| {
+        tracing::warn!("check trends of {}: {:?}", instance, e);
+        None
+    })
+}
+
 async fn post_relay(
     state: State,
     endpoint: endpoint::Endpoint<'_>,
     target: actor::Actor
 ) -> Response {
-    let remote_actor = match endpoint.remote_actor(&state.client, &target.key_id(), &state.priv_key).await {
+    let remote_actor = match endpoint.remote_actor(&target.key_id(), &state.priv_key).await {
         Ok(remote_actor) => remote_actor,
         Err(e) => {
             return (
@@ -114,7 +274,7 @@ async fn post_relay(
             ).into_response();
         }
     };
-    let object_type = action.object
+    let object_type = action.object.as_ref()
         .and_then(|object| object.get("type").cloned())
         .and_then(|object_type| object_type.as_str().map(std::string::ToString::to_string));
 
@@ -122,28 +282,34 @@ async fn post_relay(
         let priv_key = state.priv_key.clone();
         let client = state.client.clone();
         tokio::spawn(async move {
-            let accept_id = format!(
-                "https://{}/activity/accept/{}/{}",
+            let rejection = rejection_of(&state, &target).await;
+            let (action_type, path) = match rejection {
+                Some(_) => ("Reject", "reject"),
+                None => ("Accept", "accept"),
+            };
+            let response_id = format!(
+                "https://{}/activity/{}/{}/{}",
                 state.hostname,
+                path,
                 urlencoding::encode(&target.uri()),
                 urlencoding::encode(&remote_actor.inbox),
             );
-            let accept = activitypub::Action {
+            let response = activitypub::Action {
                 jsonld_context: serde_json::Value::String("https://www.w3.org/ns/activitystreams".to_string()),
-                action_type: "Accept".to_string(),
+                action_type: action_type.to_string(),
                 actor: target.uri(),
                 to: Some(json!(remote_actor.id.clone())),
-                id: accept_id,
+                id: response_id,
                 object: Some(endpoint.payload),
             };
             let result = send::send(
                 client.as_ref(), &remote_actor.inbox,
                 &target.key_id(),
                 &priv_key,
-                &accept,
+                &response,
             ).await;
-            match result {
-                Ok(()) => {
+            match (result, rejection) {
+                (Ok(()), None) => {
                     match state.database.add_follow(
                         &remote_actor.id,
                         &remote_actor.inbox,
@@ -156,8 +322,11 @@ async fn post_relay(
                         }
                     }
                 }
-                Err(e) => {
-                    tracing::error!("post accept: {}", e);
+                (Ok(()), Some(rejection)) => {
+                    tracing::info!("rejected follow of {} by {}: {}", target.uri(), remote_actor.id, rejection);
+                }
+                (Err(e), _) => {
+                    tracing::error!("post {}: {}", path, e);
                 }
             }
         });
@@ -184,11 +353,101 @@ async fn post_relay(
                  ).into_response()
             }
         }
+    } else if action.action_type == "Create"
+        && target.kind == actor::ActorKind::CompletionRelay
+        && action.object.as_ref().map_or(false, |object| mention::is_addressed_to(object, &target)) {
+        let mention = mention::Mention {
+            actor: Arc::new(target),
+            author: remote_actor,
+            object: action.object.unwrap_or_default(),
+        };
+        tokio::spawn(mention::handle(mention, state.database, state.hostname, state.priv_key, state.client));
+
+        (StatusCode::ACCEPTED,
+         [("content-type", "application/activity+json")],
+         "{}"
+        ).into_response()
+    } else if (action.action_type == "Create" || action.action_type == "Announce")
+        && target.kind == actor::ActorKind::CompletionRelay {
+        relay_inbound(state, &remote_actor, &target, action.object).await
     } else {
         (StatusCode::BAD_REQUEST, "Not a recognized request").into_response()
     }
 }
 
+/// Monitors posts pushed to the completion relay by instances following it.
+async fn relay_inbound(
+    state: State,
+    remote_actor: &activitypub::Actor,
+    target: &actor::Actor,
+    object: Option<serde_json::Value>,
+) -> Response {
+    let uri = match &object {
+        Some(serde_json::Value::String(uri)) => Some(uri.clone()),
+        Some(object) => object.get("id")
+            .and_then(|id| id.as_str())
+            .map(std::string::ToString::to_string),
+        None => None,
+    };
+    let Some(uri) = uri else {
+        return (StatusCode::BAD_REQUEST, "Object missing").into_response();
+    };
+    let in_reply_to = object.as_ref()
+        .and_then(|object| object.get("inReplyTo"))
+        .and_then(|in_reply_to| match in_reply_to {
+            serde_json::Value::String(uri) => Some(uri.clone()),
+            in_reply_to => in_reply_to.get("id")
+                .and_then(|id| id.as_str())
+                .map(std::string::ToString::to_string),
+        });
+
+    // Posts are delivered by their authors, so find the follower on the same instance.
+    let sender_host = reqwest::Url::parse(&remote_actor.id)
+        .ok()
+        .and_then(|url| url.domain().map(str::to_lowercase));
+    let followers = match state.database.get_following_remote_actors(target).await {
+        Ok(followers) => followers,
+        Err(e) => {
+            tracing::error!("get_following_remote_actors: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("{}", e)
+            ).into_response();
+        }
+    };
+    let Some(follower) = followers.into_iter().find(|follower| follower.host() == sender_host) else {
+        return (StatusCode::FORBIDDEN, "Not following").into_response();
+    };
+
+    let mut post = post::Post::from_uri(uri);
+    post.created_at = object.as_ref()
+        .and_then(|object| object.get("published"))
+        .and_then(|published| published.as_str())
+        .map(std::string::ToString::to_string);
+    // Replies are completed as part of their root thread.
+    if in_reply_to.is_some() {
+        post.in_reply_to = in_reply_to;
+        state.ancestors.queue(Arc::new(follower), post);
+        return (StatusCode::ACCEPTED,
+                [("content-type", "application/activity+json")],
+                "{}"
+        ).into_response();
+    }
+    match state.database.monitor_posts(&follower, std::iter::once(post)).await {
+        Ok(()) => {
+            (StatusCode::ACCEPTED,
+             [("content-type", "application/activity+json")],
+             "{}"
+            ).into_response()
+        }
+        Err(e) => {
+            tracing::error!("monitor_posts: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR,
+             format!("{}", e)
+            ).into_response()
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     exit_on_panic();
@@ -210,39 +469,58 @@ async fn main() {
     let pub_key = config.pub_key();
 
     let database = db::Database::connect(&config.db).await;
-    let client = Arc::new(
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .user_agent(concat!(
-                env!("CARGO_PKG_NAME"),
-                "/",
-                env!("CARGO_PKG_VERSION"),
-            ))
-            .pool_max_idle_per_host(1)
-            .pool_idle_timeout(Some(Duration::from_secs(5)))
-            .build()
-            .unwrap()
-    );
+    for (host, token) in &config.api_tokens {
+        database.set_instance_token(&host.to_lowercase(), token)
+            .await
+            .expect("set api token");
+    }
+    request::configure(config.requests.clone());
+    let client_builder = || reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION"),
+        ))
+        .pool_max_idle_per_host(1)
+        .pool_idle_timeout(Some(Duration::from_secs(5)))
+        .connect_timeout(Duration::from_secs(config.requests.connect_timeout));
+    let client = Arc::new(client_builder().build().unwrap());
+    fetch::configure(client_builder().redirect(reqwest::redirect::Policy::none()).build().unwrap());
     let hostname = Arc::new(config.hostname.clone());
     let completion_actor = actor::Actor {
         host: hostname.clone(),
         kind: actor::ActorKind::CompletionRelay,
     };
-    let tx = relay::spawn(client.clone(), hostname.clone(), priv_key.clone());
+    let tx = relay::spawn(client.clone(), hostname.clone(), priv_key.clone(), config.unlisted_replies.clone(), database.clone());
     trends::spawn(database.clone(), tx.clone(), client.clone());
-    timeline::spawn(completion_actor.clone(), database.clone(), client.clone());
-    descendants::spawn(database.clone(), client.clone());
+    let ancestors = ancestors::spawn(completion_actor.clone(), database.clone(), client.clone(), priv_key.clone(), tx.clone());
+    timeline::spawn(completion_actor.clone(), database.clone(), client.clone(), ancestors.clone(), config.timeline_max_pages, config.monitor_quoted);
+    descendants::spawn(database.clone(), client.clone(), descendants::Options {
+        key_id: completion_actor.key_id(),
+        private_key: priv_key.clone(),
+        retention: config.retention.clone(),
+        reply_check: config.reply_check.clone(),
+        crawl: config.crawl.clone(),
+    });
+    retention::spawn(database.clone(), config.retention.clone());
     completion::spawn(completion_actor.clone(), database.clone(), tx.clone());
 
     let app = Router::new()
         .route("/completion", get(get_completion_actor).post(post_completion_relay))
         .route("/trends/:instance", get(get_trends_actor).post(post_trends_relay))
+        .route("/instance/:host/token", post(post_instance_token))
+        .route("/.well-known/webfinger", get(get_webfinger))
+        .route("/note/:id", get(get_note))
+        .route("/note/:id/activity", get(get_note_create))
+        .route("/hosts/backoff", get(get_host_backoffs))
         .with_state(State {
             database,
             client,
             hostname,
             priv_key,
             pub_key,
+            ancestors,
         })
         .merge(SpaRouter::new("/", "static"));
 
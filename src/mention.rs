@@ -0,0 +1,147 @@
+use std::{collections::HashSet, sync::Arc};
+use serde_json::{json, Value};
+use sigh::PrivateKey;
+use reqwest::Client;
+use crate::{activitypub, actor::{Actor, RemoteActor}, db::Database, error::Error, replies, send};
+
+// Posts followed per mention
+const MAX_LINKS: usize = 5;
+
+pub struct Mention {
+    pub actor: Arc<Actor>,
+    pub author: activitypub::Actor,
+    pub object: Value,
+}
+
+fn strings_of(value: &Value) -> Vec<&str> {
+    match value {
+        Value::String(string) => vec![string.as_str()],
+        Value::Array(values) => values.iter().filter_map(|value| value.as_str()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Whether a post mentions the actor or is a direct message to it
+pub fn is_addressed_to(object: &Value, actor: &Actor) -> bool {
+    let uri = actor.uri();
+    let mentioned = object["tag"].as_array()
+        .into_iter()
+        .flatten()
+        .any(|tag| tag["type"] == "Mention" && tag["href"] == uri.as_str());
+    let addressed = strings_of(&object["to"]).into_iter()
+        .chain(strings_of(&object["cc"]))
+        .any(|address| address == uri);
+    mentioned || addressed
+}
+
+// Links in the content, leaving out mentions and hashtags
+fn links_of(object: &Value) -> Vec<String> {
+    let tags: HashSet<&str> = object["tag"].as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tag| tag["href"].as_str())
+        .collect();
+    let content = object["content"].as_str().unwrap_or_default();
+    let mut links = vec![];
+    for token in content.split(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>')) {
+        if !token.starts_with("https://") || tags.contains(token) || links.iter().any(|link| link == token) {
+            continue;
+        }
+        links.push(token.replace("&amp;", "&"));
+        if links.len() >= MAX_LINKS {
+            break;
+        }
+    }
+    links
+}
+
+async fn monitor_links(mention: &Mention, follower: &RemoteActor, db: &Database, private_key: &PrivateKey) -> String {
+    let links = links_of(&mention.object);
+    if links.is_empty() {
+        return "Mention me with a link to a post to have its replies delivered to your instance.".to_string();
+    }
+    let mut lines = vec![];
+    for link in links {
+        let line = match replies::get_root_of(&link, &mention.actor.key_id(), private_key).await {
+            Ok(root) => match db.monitor_post(follower, &root).await {
+                Ok(true) => format!("Now completing the thread of {}", escape(&root.uri)),
+                Ok(false) => format!("Already completing the thread of {}", escape(&root.uri)),
+                Err(e) => {
+                    tracing::error!("mention: monitor {}: {:?}", root.uri, e);
+                    format!("Failed to monitor {}", escape(&root.uri))
+                },
+            },
+            Err(e) => {
+                tracing::warn!("mention: resolve {}: {:?}", link, e);
+                format!("Could not fetch {}", escape(&link))
+            },
+        };
+        lines.push(line);
+    }
+    lines.join("<br>")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The reply is kept so that its id can be dereferenced
+async fn reply(mention: &Mention, text: &str, hostname: &str, db: &Database, private_key: &PrivateKey, client: &Client) -> Result<(), Error> {
+    let actor_uri = mention.actor.uri();
+    let author = &mention.author;
+    let author_host = reqwest::Url::parse(&author.id).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let handle = format!("@{}@{}", author.preferred_username.as_deref().unwrap_or("user"), author_host);
+    let note_id = format!("https://{}/note/{}", hostname, chrono::Utc::now().timestamp_nanos());
+    let note = activitypub::Note {
+        note_type: "Note".to_string(),
+        id: note_id.clone(),
+        attributed_to: actor_uri.clone(),
+        in_reply_to: mention.object["id"].as_str().map(str::to_string),
+        to: vec![author.id.clone()],
+        tag: vec![activitypub::Tag {
+            tag_type: "Mention".to_string(),
+            href: author.id.clone(),
+            name: handle.clone(),
+        }],
+        content: format!("<p><span class=\"h-card\"><a href=\"{}\" class=\"u-url mention\">{}</a></span> {}</p>",
+                         escape(&author.id), escape(&handle), text),
+        published: chrono::Utc::now().to_rfc3339(),
+    };
+    let create = activitypub::Action {
+        jsonld_context: json!("https://www.w3.org/ns/activitystreams"),
+        action_type: "Create".to_string(),
+        id: format!("{note_id}/activity"),
+        actor: actor_uri,
+        to: Some(json!([author.id])),
+        object: Some(note),
+    };
+    db.add_note(&note_id, &serde_json::to_string(&create)?).await?;
+    send::send(client, &author.inbox, &mention.actor.key_id(), private_key, &create).await
+}
+
+/// Monitors the threads of the posts linked by a mention for the instance of
+/// its author, and replies with what was done.
+pub async fn handle(mention: Mention, db: Database, hostname: Arc<String>, private_key: PrivateKey, client: Arc<Client>) {
+    let author_host = reqwest::Url::parse(&mention.author.id)
+        .ok()
+        .and_then(|url| url.domain().map(str::to_lowercase));
+    let follower = match db.get_following_remote_actors(&mention.actor).await {
+        Ok(followers) => followers.into_iter().find(|follower| follower.host() == author_host),
+        Err(e) => {
+            tracing::error!("mention: get_following_remote_actors: {}", e);
+            return;
+        },
+    };
+    let text = match follower {
+        Some(follower) => monitor_links(&mention, &follower, &db, &private_key).await,
+        None => "Your instance does not follow this relay yet.".to_string(),
+    };
+    if let Err(e) = reply(&mention, &text, &hostname, &db, &private_key, &client).await {
+        tracing::error!("mention: reply to {}: {:?}", mention.author.id, e);
+    }
+}
@@ -1,5 +1,6 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use serde::Deserialize;
+use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
 
 #[allow(non_snake_case)]
 #[derive(Deserialize, Clone, Debug)]
@@ -13,8 +14,51 @@ pub struct Post {
     pub created_at: Option<String>,
     #[serde(alias = "replyId")]
     pub in_reply_to_id: Option<String>,
+    /// Uri of the parent, resolved within the thread
+    #[serde(skip)]
+    pub in_reply_to: Option<String>,
+    /// A boost, or on Misskey a renote which may also be a quote
     #[serde(alias = "renote")]
     pub reblog: Option<Box<Post>>,
+    /// The quoted post of Mastodon and Akkoma quotes
+    #[serde(default, deserialize_with = "deserialize_quote")]
+    pub quote: Option<Box<Post>>,
+    #[serde(alias = "repliesCount")]
+    pub replies_count: Option<i64>,
+    /// Visibility as named by the origin software
+    pub visibility: Option<String>,
+    /// Misskey posts that are not federated at all
+    #[serde(default, rename = "localOnly")]
+    pub local_only: bool,
+    // A Misskey renote with any content of its own is a quote
+    pub text: Option<String>,
+    pub cw: Option<String>,
+    #[serde(default, rename = "fileIds")]
+    pub file_ids: Vec<String>,
+    pub poll: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Unlisted,
+    Followers,
+    Direct,
+    /// Akkoma local-only posts
+    Local,
+}
+
+// Akkoma embeds the quoted status, Mastodon wraps it with the state of the
+// quote approval. Quotes that can't be read are treated as absent.
+fn deserialize_quote<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Box<Post>>, D::Error> {
+    let quote = match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Object(mut quote)) => match quote.remove("quoted_status") {
+            Some(quoted_status) => quoted_status,
+            None => Value::Object(quote),
+        },
+        _ => return Ok(None),
+    };
+    Ok(serde_json::from_value(quote).ok().map(Box::new))
 }
 
 fn fetch_time() -> i64 {
@@ -25,6 +69,26 @@ fn fetch_time() -> i64 {
 }
 
 impl Post {
+    pub fn from_uri(uri: String) -> Self {
+        Post {
+            uri,
+            fetch_time: fetch_time(),
+            timeline_id: None,
+            created_at: None,
+            in_reply_to_id: None,
+            in_reply_to: None,
+            reblog: None,
+            quote: None,
+            replies_count: None,
+            visibility: None,
+            local_only: false,
+            text: None,
+            cw: None,
+            file_ids: vec![],
+            poll: None,
+        }
+    }
+
     pub fn host(&self) -> Option<String> {
         reqwest::Url::parse(&self.uri)
             .ok()
@@ -33,22 +97,82 @@ impl Post {
             )
     }
 
-    pub fn id(&self) -> Option<String> {
-        reqwest::Url::parse(&self.uri)
-            .ok()?
-            .path_segments()?
-            .last()
-            .map(|id| id.to_string())
-    }
-
+    /// The boosted post of a boost, otherwise the post itself. Quotes are
+    /// posts in their own right.
     pub fn origin(&self) -> Self {
         match &self.reblog {
-            Some(origin) => (**origin).clone(),
-            None => self.clone()
+            Some(origin) if !self.is_quote() => (**origin).clone(),
+            _ => self.clone()
+        }
+    }
+
+    pub fn is_quote(&self) -> bool {
+        self.quote.is_some() || (self.reblog.is_some() &&
+            (self.text.is_some() || self.cw.is_some() || !self.file_ids.is_empty() || self.poll.is_some()))
+    }
+
+    /// The post quoted by a quote
+    pub fn quoted(&self) -> Option<Post> {
+        if !self.is_quote() {
+            return None;
         }
+        self.quote.as_ref()
+            .or(self.reblog.as_ref())
+            .map(|quoted| (**quoted).clone())
     }
     
+    /// Creation time as a unix timestamp
+    pub fn created_time(&self) -> Option<i64> {
+        self.created_at.as_ref()
+            .and_then(|created_at| chrono::DateTime::parse_from_rfc3339(created_at).ok())
+            .map(|created_at| created_at.timestamp())
+    }
+
     pub fn is_reply(&self) -> bool {
         self.in_reply_to_id.is_some()
     }
+
+    /// Posts of unknown visibility are assumed to be public, as they were
+    /// found through public endpoints.
+    pub fn visibility(&self) -> Visibility {
+        match self.visibility.as_deref() {
+            Some("unlisted") | Some("home") => Visibility::Unlisted,
+            Some("private") | Some("followers") => Visibility::Followers,
+            Some("direct") | Some("specified") => Visibility::Direct,
+            Some("local") => Visibility::Local,
+            _ => Visibility::Public,
+        }
+    }
+
+    /// Whether a relay may announce this post. Local-only and followers-only
+    /// posts never are, unlisted replies only if `unlisted_replies` is set.
+    pub fn is_announceable(&self, unlisted_replies: bool) -> bool {
+        if self.local_only {
+            return false;
+        }
+        match self.visibility() {
+            Visibility::Public => true,
+            Visibility::Unlisted => unlisted_replies || !self.is_reply(),
+            Visibility::Followers | Visibility::Direct | Visibility::Local => false,
+        }
+    }
+}
+
+/// Resolves the parent uris of the replies of a thread fetched through an
+/// api, where parents are only referred to by their api ids. The root is
+/// only known by the api id it was resolved to.
+pub fn link_parents(root: &Post, replies: &mut [Post]) {
+    let mut uris: HashMap<String, String> = replies.iter()
+        .filter_map(|reply| reply.timeline_id.clone().map(|id| (id, reply.uri.clone())))
+        .collect();
+    if let Some(id) = root.timeline_id.clone() {
+        uris.insert(id, root.uri.clone());
+    }
+    for reply in replies {
+        if reply.in_reply_to.is_none() {
+            reply.in_reply_to = reply.in_reply_to_id.as_ref()
+                .and_then(|id| uris.get(id))
+                .cloned();
+        }
+    }
 }
@@ -5,10 +5,13 @@ use sigh::PrivateKey;
 use tokio::{
     sync::mpsc::{channel, Sender},
 };
-use crate::{post::Post, send, actor::{Actor, RemoteActor, ActorKind::CompletionRelay}};
+use crate::{post::Post, send, actor::{Actor, RemoteActor, ActorKind::CompletionRelay}, config::UnlistedReplies, db::Database};
 
 struct Job {
     post_uri: Arc<String>,
+    remote_actor: Arc<RemoteActor>,
+    // Completion tracks deliveries to release replies after their parents
+    track_delivery: bool,
     actor_id: Arc<String>,
     body: Arc<Vec<u8>>,
     key_id: String,
@@ -16,11 +19,17 @@ struct Job {
     inbox_url: reqwest::Url,
 }
 
-fn spawn_worker(client: Arc<reqwest::Client>) -> FutureSender<Job> {
+async fn set_delivered(db: &Database, remote_actor: &RemoteActor, post_uri: &str) {
+    if let Err(e) = db.set_delivered(remote_actor, post_uri).await {
+        tracing::error!("relay::set_delivered {:?}", e);
+    }
+}
+
+fn spawn_worker(client: Arc<reqwest::Client>, db: Database) -> FutureSender<Job> {
     let (tx, mut rx) = future_channel(1024);
 
     tokio::spawn(async move {
-        while let Some(Job { post_uri, actor_id, key_id, private_key, body, inbox_url }) = rx.next().await {
+        while let Some(Job { post_uri, remote_actor, track_delivery, actor_id, key_id, private_key, body, inbox_url }) = rx.next().await {
             tracing::debug!("relay {} from {} to {}", post_uri, actor_id, inbox_url);
             if let Err(e) = send::send_raw(
                 &client, inbox_url.as_str(),
@@ -29,6 +38,9 @@ fn spawn_worker(client: Arc<reqwest::Client>) -> FutureSender<Job> {
                 tracing::error!("relay::send {:?}", e);
             } else {
                 // success
+                if track_delivery {
+                    set_delivered(&db, &remote_actor, &post_uri).await;
+                }
                 systemd::daemon::notify(
                     false, [
                         (systemd::daemon::STATE_WATCHDOG, "1")
@@ -47,6 +59,8 @@ pub fn spawn(
     client: Arc<reqwest::Client>,
     hostname: Arc<String>,
     private_key: PrivateKey,
+    unlisted_replies: UnlistedReplies,
+    db: Database,
     ) -> Sender<(Arc<Actor>, Arc<RemoteActor>, Arc<Post>)> {
     let private_key = Arc::new(private_key);
     let (tx, mut rx) = channel::<(Arc<Actor>, Arc<RemoteActor>, Arc<Post>)>(16);
@@ -56,6 +70,15 @@ pub fn spawn(
 
         while let Some((actor, remote_actor, post)) = rx.recv().await {
             let post = post.origin();
+            let track_delivery = matches!(actor.kind, CompletionRelay);
+            if !post.is_announceable(unlisted_replies.allowed(&actor)) {
+                tracing::debug!("not relaying {} with visibility {:?}", post.uri, post.visibility());
+                // Nothing to wait for
+                if track_delivery {
+                    set_delivered(&db, &remote_actor, &post.uri).await;
+                }
+                continue;
+            }
 
             let Ok(inbox_url) = reqwest::Url::parse(&remote_actor.inbox) else { continue; };
             let Ok(post_uri) = reqwest::Url::parse(&post.uri) else { continue; };
@@ -82,10 +105,12 @@ pub fn spawn(
 
             // Lookup/create worker queue per inbox.
             let tx = workers.entry(inbox_url.host_str().unwrap_or("").to_string())
-                .or_insert_with(|| spawn_worker(client.clone()));
+                .or_insert_with(|| spawn_worker(client.clone(), db.clone()));
             // Create queue item.
             let job = Job {
                 post_uri: Arc::new(post.uri.to_string()),
+                remote_actor: remote_actor.clone(),
+                track_delivery,
                 actor_id: actor_id.clone(),
                 body: body.clone(),
                 key_id: actor.key_id(),
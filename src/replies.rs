@@ -0,0 +1,194 @@
+use std::collections::{HashSet, VecDeque};
+use serde_json::Value;
+use reqwest::Url;
+use sigh::PrivateKey;
+use crate::{post::Post, fetch::authorized_fetch, error::Error};
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const MAX_DEPTH: usize = 16;
+// Upper bound of fetches per thread
+const MAX_REQUESTS: usize = 200;
+// Upper bound of hosts fetched from per thread
+const MAX_HOSTS: usize = 20;
+// Replies followed up to their root thread
+const MAX_ANCESTORS: usize = 16;
+// Object types that are monitored as posts
+const POST_TYPES: &[&str] = &["Note", "Article", "Page"];
+
+struct Walker<'a> {
+    key_id: &'a str,
+    private_key: &'a PrivateKey,
+    budget: usize,
+    hosts: HashSet<String>,
+}
+
+fn object_id(object: &Value) -> Option<String> {
+    match object {
+        Value::String(uri) => Some(uri.clone()),
+        Value::Object(object) => object.get("id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+impl Walker<'_> {
+    async fn fetch(&mut self, uri: &str) -> Result<Value, Error> {
+        if self.budget == 0 {
+            return Err(Error::Api(format!("Request budget exhausted before {uri}")));
+        }
+        let host = reqwest::Url::parse(uri).ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .ok_or(Error::InvalidUri)?;
+        if !self.hosts.contains(&host) && self.hosts.len() >= MAX_HOSTS {
+            return Err(Error::Api(format!("Host budget exhausted before {uri}")));
+        }
+        self.hosts.insert(host);
+        self.budget -= 1;
+        authorized_fetch(uri, self.key_id, self.private_key).await
+    }
+
+    // Embedded objects are used as they are, links are dereferenced.
+    async fn dereference(&mut self, value: &Value) -> Result<Option<Value>, Error> {
+        match value {
+            Value::String(uri) => Ok(Some(self.fetch(uri).await?)),
+            Value::Object(_) => Ok(Some(value.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Collects the direct replies of an object by walking its `replies`
+    /// collection page by page.
+    async fn replies_of(&mut self, object: &Value) -> Result<Vec<Value>, Error> {
+        let mut replies = vec![];
+        let Some(collection) = self.dereference(&object["replies"]).await? else {
+            return Ok(replies);
+        };
+        let mut seen_pages = HashSet::new();
+        let mut page = match self.dereference(&collection["first"]).await? {
+            Some(first) => {
+                replies.extend(Self::items_of(&collection));
+                Some(first)
+            },
+            None => Some(collection),
+        };
+        while let Some(current) = page.take() {
+            replies.extend(Self::items_of(&current));
+            let Some(next) = object_id(&current["next"]) else { break };
+            if !seen_pages.insert(next.clone()) {
+                break;
+            }
+            page = self.dereference(&current["next"]).await?;
+        }
+        Ok(replies)
+    }
+
+    fn items_of(page: &Value) -> Vec<Value> {
+        ["orderedItems", "items"].iter()
+            .filter_map(|key| page[key].as_array())
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+// Public posts address the public collection, unlisted ones only cc it.
+fn visibility_of(object: &Value) -> &'static str {
+    let addresses = |field: &str| match &object[field] {
+        Value::String(address) => vec![address.clone()],
+        Value::Array(addresses) => addresses.iter().filter_map(object_id).collect(),
+        _ => vec![],
+    };
+    let is_public = |address: &String| matches!(address.as_str(), PUBLIC | "as:Public" | "Public");
+    if addresses("to").iter().any(is_public) {
+        "public"
+    } else if addresses("cc").iter().any(is_public) {
+        "unlisted"
+    } else {
+        "private"
+    }
+}
+
+fn post_of(uri: String, object: Option<&Value>) -> Post {
+    let mut post = Post::from_uri(uri);
+    if let Some(object) = object {
+        post.created_at = object["published"].as_str().map(str::to_string);
+        post.in_reply_to_id = object_id(&object["inReplyTo"]);
+        post.in_reply_to = post.in_reply_to_id.clone();
+        post.visibility = Some(visibility_of(object).to_string());
+    }
+    post
+}
+
+/// Fetches descendants of a post through the `replies` collection of its
+/// ActivityPub object, which works for any server that exposes one.
+pub async fn get_descendants_of(post: &Post, key_id: &str, private_key: &PrivateKey) -> Result<Vec<Post>, Error> {
+    let mut walker = Walker { key_id, private_key, budget: MAX_REQUESTS, hosts: HashSet::new() };
+    let root = walker.fetch(&post.uri).await?;
+
+    let mut descendants = vec![];
+    let mut seen = HashSet::from([post.uri.clone()]);
+    let mut queue = VecDeque::from([(root, 0)]);
+    while let Some((object, depth)) = queue.pop_front() {
+        if depth >= MAX_DEPTH {
+            continue;
+        }
+        let replies = match walker.replies_of(&object).await {
+            Ok(replies) => replies,
+            Err(e) => {
+                tracing::warn!("replies: walk replies of {:?}: {:?}", object_id(&object), e);
+                continue;
+            },
+        };
+        for reply in replies {
+            let Some(uri) = object_id(&reply) else { continue };
+            if !seen.insert(uri.clone()) {
+                continue;
+            }
+            let reply = match reply {
+                Value::Object(_) if reply.get("replies").is_some() => Some(reply),
+                _ => match walker.fetch(&uri).await {
+                    Ok(reply) => Some(reply),
+                    Err(e) => {
+                        tracing::warn!("replies: fetch {}: {:?}", uri, e);
+                        None
+                    },
+                },
+            };
+            descendants.push(post_of(uri, reply.as_ref()));
+            if let Some(reply) = reply {
+                queue.push_back((reply, depth + 1));
+            }
+        }
+    }
+    Ok(descendants)
+}
+
+// Fetches a post, which must be served by the origin of its id
+async fn fetch_post(uri: &str, key_id: &str, private_key: &PrivateKey) -> Result<Value, Error> {
+    let url = Url::parse(uri).map_err(|_| Error::InvalidUri)?;
+    let object: Value = authorized_fetch(uri, key_id, private_key).await?;
+    if !object["type"].as_str().map_or(false, |object_type| POST_TYPES.contains(&object_type)) {
+        return Err(Error::Api(format!("{uri} is not a post")));
+    }
+    let id = object["id"].as_str()
+        .and_then(|id| Url::parse(id).ok())
+        .ok_or_else(|| Error::Api(format!("{uri} has no id")))?;
+    if id.origin() != url.origin() {
+        return Err(Error::Api(format!("{uri} serves {id} of another origin")));
+    }
+    Ok(object)
+}
+
+/// Walks up the `inReplyTo` of a post's ActivityPub object to the root of
+/// its thread, which works for any server.
+pub async fn get_root_of(uri: &str, key_id: &str, private_key: &PrivateKey) -> Result<Post, Error> {
+    let mut object = fetch_post(uri, key_id, private_key).await?;
+    for _ in 0..MAX_ANCESTORS {
+        let Some(parent) = object_id(&object["inReplyTo"]) else { break };
+        object = fetch_post(&parent, key_id, private_key).await?;
+    }
+    let root = object_id(&object)
+        .ok_or_else(|| Error::Api(format!("{uri} has no id")))?;
+    Ok(post_of(root, Some(&object)))
+}
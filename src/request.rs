@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+use tokio::time::{sleep, sleep_until, Instant};
+use rand::Rng;
+use serde::{Serialize, de::DeserializeOwned};
+use reqwest::{header::HeaderMap, Method, RequestBuilder, Response, StatusCode};
+use crate::{config::Requests, error::Error};
+
+// Pause of a host throttling without saying for how long, doubled on
+// every further throttled response.
+const DEFAULT_PAUSE: Duration = Duration::from_secs(60);
+// Idle hosts are forgotten once this many are known
+const MAX_IDLE_HOSTS: usize = 1024;
+
+static REQUESTS: Mutex<Option<Requests>> = Mutex::new(None);
+static HOSTS: Mutex<BTreeMap<String, Host>> = Mutex::new(BTreeMap::new());
+
+/// Pacing of the requests to a host
+struct Host {
+    next_turn: Instant,
+    paused_until: Option<Instant>,
+    /// Throttled responses in a row
+    strikes: u32,
+    reason: Option<String>,
+}
+
+/// The backoff state of a paused host
+#[derive(Serialize)]
+pub struct Backoff {
+    pub host: String,
+    /// Seconds until requests are sent again
+    pub paused_for: u64,
+    pub strikes: u32,
+    pub reason: Option<String>,
+}
+
+/// Sets the limits of api requests, once at startup.
+pub fn configure(requests: Requests) {
+    if REQUESTS.lock().unwrap().replace(requests).is_some() {
+        tracing::warn!("request limits are already configured");
+    }
+}
+
+fn limits() -> Requests {
+    REQUESTS.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Waits for the turn of the host, as paced by the configured rate and
+/// any pause. Fails right away if the turn is further than `max_wait`.
+pub async fn wait_turn(host: &str, max_wait: Duration) -> Result<(), Error> {
+    let interval = Duration::from_secs_f64(1.0 / limits().per_host_rate.max(0.001));
+    let turn = {
+        let mut hosts = HOSTS.lock().unwrap();
+        let now = Instant::now();
+        if hosts.len() > MAX_IDLE_HOSTS {
+            hosts.retain(|_, state| state.next_turn > now || state.paused_until.map_or(false, |until| until > now));
+        }
+        let state = hosts.entry(host.to_string()).or_insert_with(|| Host {
+            next_turn: now,
+            paused_until: None,
+            strikes: 0,
+            reason: None,
+        });
+        let turn = now.max(state.next_turn).max(state.paused_until.unwrap_or(now));
+        if turn - now > max_wait {
+            return Err(Error::Throttled(host.to_string(), (turn - now).as_secs()));
+        }
+        state.next_turn = turn + interval;
+        turn
+    };
+    sleep_until(turn).await;
+    Ok(())
+}
+
+// `Retry-After` is in seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("retry-after")?.to_str().ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => chrono::DateTime::parse_from_rfc2822(value).ok()
+            .and_then(|date| (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()),
+    }
+}
+
+// Mastodon resets its rate limits at a timestamp, Misskey after seconds
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("x-ratelimit-reset")?.to_str().ok()?;
+    match value.parse::<f64>() {
+        Ok(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(value).ok()
+            .and_then(|date| (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()),
+    }
+}
+
+/// Pauses the host if the response says it is throttling, or that the
+/// rate limit is used up.
+pub fn observe(host: &str, res: &Response) {
+    let headers = res.headers();
+    let exhausted = headers.get("x-ratelimit-remaining")
+        .and_then(|remaining| remaining.to_str().ok())
+        .and_then(|remaining| remaining.parse::<f64>().ok())
+        .map_or(false, |remaining| remaining < 1.0);
+    let mut hosts = HOSTS.lock().unwrap();
+    let Some(state) = hosts.get_mut(host) else { return };
+    let pause = match res.status() {
+        StatusCode::TOO_MANY_REQUESTS => {
+            state.strikes += 1;
+            let pause = retry_after(headers)
+                .or_else(|| rate_limit_reset(headers))
+                .unwrap_or(DEFAULT_PAUSE * 2u32.saturating_pow(state.strikes - 1));
+            Some((pause, "too many requests"))
+        },
+        StatusCode::SERVICE_UNAVAILABLE => retry_after(headers).map(|pause| (pause, "service unavailable")),
+        _ if exhausted => rate_limit_reset(headers).map(|pause| (pause, "rate limit used up")),
+        status => {
+            if status.is_success() {
+                state.strikes = 0;
+            }
+            None
+        },
+    };
+    if let Some((pause, reason)) = pause {
+        let pause = pause.min(Duration::from_secs(limits().max_pause));
+        tracing::info!("pausing requests to {} for {:?}: {}", host, pause, reason);
+        state.paused_until = Some(Instant::now() + pause);
+        state.reason = Some(reason.to_string());
+    }
+}
+
+/// The hosts that are currently paused
+pub fn backoffs() -> Vec<Backoff> {
+    let now = Instant::now();
+    HOSTS.lock().unwrap()
+        .iter()
+        .filter_map(|(host, state)| {
+            let until = state.paused_until.filter(|until| *until > now)?;
+            Some(Backoff {
+                host: host.clone(),
+                paused_for: (until - now).as_secs(),
+                strikes: state.strikes,
+                reason: state.reason.clone(),
+            })
+        })
+        .collect()
+}
+
+/// What a request is for, which decides its timeout
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    Detect,
+    Timeline,
+    Post,
+    Context,
+}
+
+impl Operation {
+    pub fn timeout(self) -> Duration {
+        let limits = limits();
+        Duration::from_secs(match self {
+            Operation::Detect => limits.detect_timeout,
+            Operation::Timeline => limits.timeline_timeout,
+            Operation::Post => limits.post_timeout,
+            Operation::Context => limits.context_timeout,
+        })
+    }
+}
+
+/// Classifies a failed request by what went wrong.
+pub fn classify(e: reqwest::Error) -> Error {
+    let url = e.url().map(|url| url.to_string()).unwrap_or_default();
+    if e.is_timeout() {
+        Error::Timeout(url)
+    } else if e.is_connect() {
+        Error::Connect(url)
+    } else {
+        Error::Http(e)
+    }
+}
+
+fn is_retryable(result: &Result<Response, reqwest::Error>) -> bool {
+    match result {
+        Ok(res) => res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS,
+        Err(e) => e.is_timeout() || e.is_connect(),
+    }
+}
+
+/// Sends a request within the timeout of its operation, in the turn of its
+/// host. GET requests are retried on timeouts, connection failures and
+/// server errors, after an exponential backoff with jitter.
+pub async fn send(request: RequestBuilder, operation: Operation) -> Result<Response, Error> {
+    let limits = limits();
+    let (client, request) = request.timeout(operation.timeout()).build_split();
+    let request = request.map_err(classify)?;
+    let host = request.url().host_str().unwrap_or_default().to_lowercase();
+    let retries = if request.method() == Method::GET { limits.retries } else { 0 };
+    let mut delay = Duration::from_millis(limits.retry_delay);
+    let mut attempt = 0;
+    loop {
+        wait_turn(&host, operation.timeout()).await?;
+        let Some(this_request) = request.try_clone() else {
+            let res = client.execute(request).await.map_err(classify)?;
+            observe(&host, &res);
+            return Ok(res);
+        };
+        let result = client.execute(this_request).await;
+        if let Ok(res) = &result {
+            observe(&host, res);
+        }
+        if attempt >= retries || !is_retryable(&result) {
+            return result.map_err(classify);
+        }
+        attempt += 1;
+        let jittered = delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+        tracing::debug!("retrying {} in {:?}, attempt {}", request.url(), jittered, attempt);
+        sleep(jittered).await;
+        delay *= 2;
+    }
+}
+
+/// Reads a response body, failing as soon as it grows beyond the limit.
+pub async fn bytes(mut res: Response) -> Result<Vec<u8>, Error> {
+    let max = limits().max_response_size;
+    let url = res.url().to_string();
+    if res.content_length().map_or(false, |length| length > max as u64) {
+        return Err(Error::TooLarge(url, max));
+    }
+    let mut body = vec![];
+    while let Some(chunk) = res.chunk().await.map_err(classify)? {
+        if body.len() + chunk.len() > max {
+            return Err(Error::TooLarge(url, max));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+pub async fn text(res: Response) -> Result<String, Error> {
+    Ok(String::from_utf8_lossy(&bytes(res).await?).into_owned())
+}
+
+/// Parses a successful response, other statuses fail with `Error::Status`.
+pub async fn json<T: DeserializeOwned>(res: Response) -> Result<T, Error> {
+    if !res.status().is_success() {
+        return Err(Error::Status(res.url().to_string(), res.status()));
+    }
+    Ok(serde_json::from_slice(&bytes(res).await?)?)
+}
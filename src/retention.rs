@@ -0,0 +1,56 @@
+use std::time::Duration;
+use tokio::time::sleep;
+use crate::{config::Retention, error::Error, db::Database};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+// Pause between batches so that other queries get their turn.
+const BATCH_PAUSE: Duration = Duration::from_millis(100);
+
+async fn prune_batched<F, Fut>(retention: &Retention, prune_batch: F) -> Result<u64, Error>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<u64, tokio_postgres::Error>>,
+{
+    let mut pruned = 0;
+    loop {
+        let deleted = prune_batch().await?;
+        pruned += deleted;
+        if deleted < retention.delete_batch_size.try_into().unwrap_or(u64::MAX) {
+            return Ok(pruned);
+        }
+        sleep(BATCH_PAUSE).await;
+    }
+}
+
+async fn prune(db: &Database, retention: &Retention) -> Result<(), Error> {
+    let before = chrono::Utc::now().timestamp() - retention.delete_days * 86400;
+    let pruned = prune_batched(retention, || db.prune_posts(before, retention.delete_batch_size)).await?;
+    if pruned > 0 {
+        tracing::info!("retention: pruned {} posts", pruned);
+    }
+    // Reply tree cursors of Misskey threads that are no longer checked
+    let pruned = prune_batched(retention, || db.prune_children_cursors(before, retention.delete_batch_size)).await?;
+    if pruned > 0 {
+        tracing::info!("retention: pruned {} children cursors", pruned);
+    }
+    let pruned = prune_batched(retention, || db.prune_api_ids(before, retention.delete_batch_size)).await?;
+    if pruned > 0 {
+        tracing::info!("retention: pruned {} api ids", pruned);
+    }
+    let pruned = prune_batched(retention, || db.prune_notes(before, retention.delete_batch_size)).await?;
+    if pruned > 0 {
+        tracing::info!("retention: pruned {} notes", pruned);
+    }
+    Ok(())
+}
+
+pub fn spawn(db: Database, retention: Retention) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = prune(&db, &retention).await {
+                tracing::error!("retention: {:?}", e);
+            }
+            sleep(PRUNE_INTERVAL).await;
+        }
+    });
+}
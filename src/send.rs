@@ -1,8 +1,8 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use http::StatusCode;
 use serde::Serialize;
 use sigh::{PrivateKey, SigningConfig, alg::RsaSha256};
-use crate::{digest, error::Error};
+use crate::{digest, error::Error, request};
 
 pub async fn send<T: Serialize>(
     client: &reqwest::Client,
@@ -28,6 +28,9 @@ pub async fn send_raw(
     let url = reqwest::Url::parse(uri)
         .map_err(|_| Error::InvalidUri)?;
     let host = format!("{}", url.host().ok_or(Error::InvalidUri)?);
+    // Deliveries wait out any pause of the host rather than getting lost,
+    // and are signed once it is their turn.
+    request::wait_turn(&host.to_lowercase(), Duration::MAX).await?;
     let digest_header = digest::generate_header(&body)
         .map_err(|()| Error::Digest)?;
     let mut req = http::Request::builder()
@@ -45,6 +48,7 @@ pub async fn send_raw(
     let req: reqwest::Request = req.try_into()?;
     let res = client.execute(req)
         .await?;
+    request::observe(&host.to_lowercase(), &res);
     if res.status() >= StatusCode::OK && res.status() < StatusCode::MULTIPLE_CHOICES {
         Ok(())
     } else {
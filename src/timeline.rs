@@ -1,43 +1,157 @@
-use std::{sync::Arc, time::Duration};
-use tokio::time::sleep;
+use std::{sync::Arc, time::Duration, collections::HashMap};
+use futures::StreamExt;
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, sleep_until, timeout, Instant},
+};
 use reqwest::Client;
-use crate::{api::FediApi, actor::{Actor, RemoteActor}, error::Error, db::Database};
-
-async fn update_timeline(remote_actor: &RemoteActor, db: &Database, client: &Client) -> Result<(), Error> {
-    let host = remote_actor.host()
-               .ok_or_else(|| Error::Api(format!("Failed to get host of {}", remote_actor.id)))?;
-    let api = FediApi::from_host(&host, db, client).await?;
-    let latest_id = db.get_latest_id_of(remote_actor).await;
-    let posts = api.get_global_timeline(&host, &latest_id, client).await?;
+use crate::{api::FediApi, ancestors::Ancestors, post::Post, actor::{Actor, RemoteActor}, error::Error, db::Database};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+// Reconnect backoff, doubled after every failed or short-lived stream.
+const STREAM_MIN_BACKOFF: Duration = Duration::from_secs(5);
+const STREAM_MAX_BACKOFF: Duration = Duration::from_secs(1800);
+// A stream that lasted this long resets the backoff.
+const STREAM_STABLE_DURATION: Duration = Duration::from_secs(300);
+// A stream without any post for this long is considered stalled.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+struct Context {
+    actor: Arc<Actor>,
+    db: Database,
+    client: Arc<Client>,
+    ancestors: Ancestors,
+    max_pages: usize,
+    monitor_quoted: bool,
+}
+
+async fn monitor_timeline_posts(remote_actor: &Arc<RemoteActor>, posts: Vec<Post>, ctx: &Context) -> Result<(), Error> {
     if let Some(post) = posts.last() {
         let new_latest_id = post.timeline_id.clone();
-        db.monitor_posts(remote_actor,
-                         posts.into_iter()
-                              .map(|post| post.origin())
-                              .filter(|post| !post.is_reply()))
-           .await?;
-        db.update_timeline(remote_actor, &new_latest_id).await?;
+        let mut posts: Vec<Post> = posts.into_iter()
+            .map(|post| post.origin())
+            .collect();
+        if ctx.monitor_quoted {
+            let quoted: Vec<Post> = posts.iter()
+                .filter_map(|post| post.quoted())
+                .map(|quoted| quoted.origin())
+                .collect();
+            posts.extend(quoted);
+        }
+        let (replies, posts): (Vec<Post>, Vec<Post>) = posts.into_iter()
+            .partition(|post| post.is_reply());
+        ctx.db.monitor_posts(remote_actor, posts.into_iter()).await?;
+        for reply in replies {
+            ctx.ancestors.queue(remote_actor.clone(), reply);
+        }
+        ctx.db.update_timeline(remote_actor, &new_latest_id).await?;
     }
     Ok(())
 }
 
-async fn update(actor: &Actor, db: &Database, client: &Client) -> Result<(), Error> {
-    let remote_actors = db.get_following_remote_actors(actor).await?;
-    for remote_actor in remote_actors {
-        if let Err(e) = update_timeline(&remote_actor, db, client).await {
-            tracing::error!("timeline: update timline: {:?}", e);
+// Pages forward from the stored latest id, oldest posts first, so that
+// nothing between two updates is skipped. Without a stored id only the
+// newest page is taken.
+async fn update_timeline(remote_actor: &Arc<RemoteActor>, host: &str, api: &FediApi, ctx: &Context) -> Result<(), Error> {
+    let mut latest_id = ctx.db.get_latest_id_of(remote_actor).await;
+    for _ in 0..ctx.max_pages {
+        let catching_up = latest_id.is_some();
+        let posts = api.get_global_timeline(&latest_id, &ctx.client).await?;
+        if posts.is_empty() {
+            return Ok(());
+        }
+        latest_id = posts.last().and_then(|post| post.timeline_id.clone());
+        monitor_timeline_posts(remote_actor, posts, ctx).await?;
+        if !catching_up {
+            return Ok(());
+        }
+    }
+    tracing::warn!("timeline: {} is more than {} pages behind, continuing next update", host, ctx.max_pages);
+    Ok(())
+}
+
+async fn stream_timeline(remote_actor: &Arc<RemoteActor>, host: &str, api: &FediApi, ctx: &Context) -> Result<(), Error> {
+    let mut posts = api.stream_global_timeline(&ctx.client).await?;
+    tracing::info!("timeline: streaming global timeline of {}", host);
+    loop {
+        match timeout(STREAM_IDLE_TIMEOUT, posts.next()).await {
+            Ok(Some(Ok(post))) => monitor_timeline_posts(remote_actor, vec![post], ctx).await?,
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) => return Err(Error::Stream(format!("{host}: stream closed"))),
+            Err(_) => return Err(Error::Stream(format!("{host}: stream idle"))),
+        }
+    }
+}
+
+async fn follow_timeline(remote_actor: RemoteActor, ctx: Arc<Context>) {
+    let remote_actor = Arc::new(remote_actor);
+    let mut next_stream_attempt = Instant::now();
+    let mut backoff = STREAM_MIN_BACKOFF;
+    loop {
+        let host = match remote_actor.host() {
+            Some(host) => host,
+            None => {
+                tracing::error!("timeline: host unknown: {}", remote_actor.id);
+                return;
+            },
+        };
+        match FediApi::from_host(&host, &ctx.db, &ctx.client).await {
+            Ok(api) => {
+                // Catch up through the stored latest id on whatever was
+                // posted while not streaming.
+                if let Err(e) = update_timeline(&remote_actor, &host, &api, &ctx).await {
+                    tracing::error!("timeline: update timline: {:?}", e);
+                }
+                if Instant::now() >= next_stream_attempt {
+                    let started = Instant::now();
+                    if let Err(e) = stream_timeline(&remote_actor, &host, &api, &ctx).await {
+                        tracing::warn!("timeline: stream of {} unavailable, polling instead: {:?}", host, e);
+                    }
+                    if started.elapsed() >= STREAM_STABLE_DURATION {
+                        backoff = STREAM_MIN_BACKOFF;
+                    }
+                    next_stream_attempt = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(STREAM_MAX_BACKOFF);
+                }
+            },
+            Err(e) => tracing::error!("timeline: get api of {}: {:?}", host, e),
+        }
+        sleep_until(next_stream_attempt.max(Instant::now()).min(Instant::now() + POLL_INTERVAL)).await;
+    }
+}
+
+async fn update(ctx: &Arc<Context>, followers: &mut HashMap<String, JoinHandle<()>>) -> Result<(), Error> {
+    let remote_actors: Vec<RemoteActor> = ctx.db.get_following_remote_actors(&ctx.actor).await?.collect();
+    followers.retain(|id, task| {
+        let following = remote_actors.iter().any(|remote_actor| &remote_actor.id == id);
+        if !following {
+            task.abort();
         }
+        following && !task.is_finished()
+    });
+    for remote_actor in remote_actors {
+        followers.entry(remote_actor.id.clone())
+            .or_insert_with(|| tokio::spawn(follow_timeline(remote_actor, ctx.clone())));
     }
     Ok(())
 }
 
-pub fn spawn(actor: Actor, db: Database, client: Arc<Client>) {
+pub fn spawn(actor: Actor, db: Database, client: Arc<Client>, ancestors: Ancestors, max_pages: usize, monitor_quoted: bool) {
+    let ctx = Arc::new(Context {
+        actor: Arc::new(actor),
+        db,
+        client,
+        ancestors,
+        max_pages,
+        monitor_quoted,
+    });
     tokio::spawn(async move {
+        let mut followers = HashMap::new();
         loop {
-            if let Err(e) = update(&actor, &db, &client).await {
+            if let Err(e) = update(&ctx, &mut followers).await {
                 tracing::error!("timeline: update: {:?}", e);
             }
-            sleep(Duration::from_secs(60)).await;
+            sleep(POLL_INTERVAL).await;
         }
     });
 }
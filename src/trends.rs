@@ -23,12 +23,17 @@ async fn update_trends(db: &Database, tx: &Sender<(Arc<Actor>, Arc<RemoteActor>,
         if let ActorKind::TrendsRelay(instance_host) = &actor.kind {
             let api = match FediApi::from_host(instance_host, db, client).await {
                 Ok(api) => api,
+                // Reported by the trends actor, and retried on its own schedule
+                Err(e @ Error::Unsupported(..)) => {
+                    tracing::debug!("get api of {}: {:?}", instance_host, e);
+                    continue;
+                },
                 Err(e) => {
                     tracing::error!("get api of {}: {:?}", instance_host, e);
                     continue;
                 },
             };
-            match api.get_trending_posts(instance_host, client).await {
+            match api.get_trending_posts(client).await {
                 Ok(posts) => {
                     for post in posts {
                         let post = Arc::new(post);
@@ -15,6 +15,19 @@
     }
 
     setup("trends");
+    document.getElementById("token-submit").addEventListener('click', function() {
+        var host = encodeURIComponent(document.getElementById("token-host").value);
+        var resultEl = document.getElementById("token-result");
+        fetch("/instance/" + host + "/token", {
+            method: "POST",
+            headers: { "content-type": "application/json" },
+            body: JSON.stringify({ token: document.getElementById("token").value }),
+        }).then(function(res) {
+            return res.ok ? "Registered" : res.text();
+        }).then(function(text) {
+            resultEl.innerText = text;
+        });
+    });
     document.getElementById("completion-url").innerText = 
         "https://" + document.location.host + "/completion";
 })()